@@ -0,0 +1,97 @@
+use std::ptr;
+
+use crate::{byte_size_of_array, pointer_to_array};
+
+// Owning wrappers around OpenGL buffer and vertex array names. They are deleted when dropped,
+// which means they have to be dropped on the thread that owns the OpenGL context.
+//
+//     let mut vao = VertexArray::new();
+//     vao.add_buffer(&vertices, 0, 3);   // vec3 at location 0
+//     vao.set_indices(&indices);
+//     ...
+//     vao.draw();
+
+pub struct VertexBuffer {
+    id : u32,
+}
+
+impl VertexBuffer {
+    pub unsafe fn new<T>(data: &[T], usage: gl::types::GLenum) -> VertexBuffer {
+        let mut id = 0;
+        gl::GenBuffers(1, &mut id);
+        gl::BindBuffer(gl::ARRAY_BUFFER, id);
+        gl::BufferData(gl::ARRAY_BUFFER, byte_size_of_array(data), pointer_to_array(data), usage);
+        VertexBuffer { id }
+    }
+}
+
+impl Drop for VertexBuffer {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteBuffers(1, &self.id) };
+    }
+}
+
+pub struct IndexBuffer {
+    id    : u32,
+    count : i32,
+}
+
+impl IndexBuffer {
+    // Must be created while the vertex array it belongs to is bound
+    pub unsafe fn new(indices: &[u32], usage: gl::types::GLenum) -> IndexBuffer {
+        let mut id = 0;
+        gl::GenBuffers(1, &mut id);
+        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, id);
+        gl::BufferData(gl::ELEMENT_ARRAY_BUFFER, byte_size_of_array(indices), pointer_to_array(indices), usage);
+        IndexBuffer { id, count: indices.len() as i32 }
+    }
+}
+
+impl Drop for IndexBuffer {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteBuffers(1, &self.id) };
+    }
+}
+
+pub struct VertexArray {
+    id             : u32,
+    vertex_buffers : Vec<VertexBuffer>,
+    index_buffer   : Option<IndexBuffer>,
+}
+
+impl VertexArray {
+    pub unsafe fn new() -> VertexArray {
+        let mut id = 0;
+        gl::GenVertexArrays(1, &mut id);
+        VertexArray { id, vertex_buffers: vec![], index_buffer: None }
+    }
+
+    // Uploads a tightly packed array of floats, `components` of them per vertex, and points the
+    // shader input at `location` to it
+    pub unsafe fn add_buffer(&mut self, data: &[f32], location: u32, components: i32) {
+        gl::BindVertexArray(self.id);
+        let buffer = VertexBuffer::new(data, gl::STATIC_DRAW);
+        gl::VertexAttribPointer(location, components, gl::FLOAT, gl::FALSE, 0, ptr::null());
+        gl::EnableVertexAttribArray(location);
+        self.vertex_buffers.push(buffer);
+    }
+
+    pub unsafe fn set_indices(&mut self, indices: &[u32]) {
+        gl::BindVertexArray(self.id);
+        self.index_buffer = Some(IndexBuffer::new(indices, gl::STATIC_DRAW));
+    }
+
+    // Draws every index as triangles
+    pub unsafe fn draw(&self) {
+        let count = self.index_buffer.as_ref().map_or(0, |b| b.count);
+        gl::BindVertexArray(self.id);
+        gl::DrawElements(gl::TRIANGLES, count, gl::UNSIGNED_INT, ptr::null());
+    }
+}
+
+impl Drop for VertexArray {
+    fn drop(&mut self) {
+        // The buffers are dropped after this, once nothing refers to them anymore
+        unsafe { gl::DeleteVertexArrays(1, &self.id) };
+    }
+}
@@ -5,6 +5,7 @@ use std::sync::{Mutex, Arc, RwLock};
 
 mod shader;
 mod util;
+mod gpu;
 
 use glutin::event::{Event, WindowEvent, DeviceEvent, KeyboardInput, ElementState::{Pressed, Released}, VirtualKeyCode::{self, *}};
 use glutin::event_loop::ControlFlow;
@@ -38,45 +39,6 @@ fn offset<T>(n: u32) -> *const c_void {
 
 
 
-// == // Modify and complete the function below for the first task
-unsafe fn set_up_VAO(vertices: &Vec<f32>, indices: &Vec<u32>) -> u32 {
-    let mut array_ID: u32 = 0;
-    let number_of_VAOs = 1;
-
-    /* Vertex Array Object */
-    gl::GenVertexArrays(number_of_VAOs, &mut array_ID as *mut u32);
-    gl::BindVertexArray(array_ID);
-
-    /* Vertex Buffer Object for vertices */
-    let mut buffer_ID: u32 = 0;
-    let number_of_VBOs = 1;
-
-    gl::GenBuffers(number_of_VBOs, &mut buffer_ID as *mut u32);
-    gl::BindBuffer(gl::ARRAY_BUFFER, buffer_ID);
-
-    gl::BufferData(gl::ARRAY_BUFFER, byte_size_of_array(vertices), pointer_to_array(vertices), gl::STATIC_DRAW);
-
-    let vertex_attrib_ptr_idx = 0;
-    let components_per_vertex = 3;
-    let stride: i32 = 0;         //Set to 0 because input only contains vertices, and thus OpenGL can figure it out by itself
-    let first_data = ptr::null();
-    gl::VertexAttribPointer(vertex_attrib_ptr_idx, components_per_vertex, 
-                            gl::FLOAT, gl::FALSE, stride, first_data);
-
-    gl::EnableVertexAttribArray(vertex_attrib_ptr_idx);
-
-    /* Vertex Buffer Object for indices */
-    let mut idx_buffer_ID: u32 = 0;
-    let number_of_IVBOs = 1;
-
-    gl::GenBuffers(number_of_IVBOs, &mut idx_buffer_ID as *mut u32);
-    gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, idx_buffer_ID);
-
-    gl::BufferData(gl::ELEMENT_ARRAY_BUFFER, byte_size_of_array(indices), pointer_to_array(indices), gl::STATIC_DRAW);
-
-    return array_ID;
-} 
-
 fn main() {
     // Set up the necessary objects to deal with windows and event handling
     let el = glutin::event_loop::EventLoop::new();
@@ -129,7 +91,6 @@ fn main() {
         }
 
         // == // Set up your VAO here
-        let vao_num;
 
         /* Vertices used for 1c:  */
         /*let vertices: Vec<f32> = vec![-0.75, 0.75, 0.0, -0.5, 0.75, 0.0, -0.625, 1.0, 0.0,
@@ -151,9 +112,13 @@ fn main() {
         }*/
         let indices: Vec<u32> = vec![0,1,2];
 
-        unsafe {
-            vao_num = set_up_VAO(&vertices, &indices);
-        }
+        // The vertex array owns its buffers, and deletes them along with itself
+        let vao = unsafe {
+            let mut vao = gpu::VertexArray::new();
+            vao.add_buffer(&vertices, 0, 3);
+            vao.set_indices(&indices);
+            vao
+        };
 
         // Basic usage of shader helper:
         // The example code below returns a shader object, which contains the field `.program_id`.
@@ -214,11 +179,7 @@ fn main() {
                 gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
 
                 // Issue the necessary commands to draw your scene here
-                let elements_to_draw: i32 = indices.len() as i32;
-                let zero_address = ptr::null();
-                gl::BindVertexArray(vao_num);
-                
-                gl::DrawElements(gl::TRIANGLES, elements_to_draw, gl::UNSIGNED_INT, zero_address);
+                vao.draw();
 
             }
 
@@ -5,6 +5,8 @@ use std::sync::{Mutex, Arc, RwLock};
 
 mod shader;
 mod util;
+mod gpu;
+mod camera;
 
 use glutin::event::{Event, WindowEvent, DeviceEvent, KeyboardInput, ElementState::{Pressed, Released}, VirtualKeyCode::{self, *}};
 use glutin::event_loop::ControlFlow;
@@ -38,63 +40,6 @@ fn offset<T>(n: u32) -> *const c_void {
 
 
 
-// == // Modify and complete the function below for the first task
-unsafe fn set_up_VAO(vertices: &Vec<f32>, colour: &Vec<f32>, indices: &Vec<u32>) -> u32 {
-    let mut array_ID: u32 = 0;
-    let number_of_VAOs = 1;
-
-    /* Vertex Array Object */
-    gl::GenVertexArrays(number_of_VAOs, &mut array_ID as *mut u32);
-    gl::BindVertexArray(array_ID);
-
-    /* Vertex Buffer Object for vertices */
-    let mut vertex_buffer_ID: u32 = 0;
-    let number_of_VBOs = 1;
-
-    gl::GenBuffers(number_of_VBOs, &mut vertex_buffer_ID as *mut u32);
-    gl::BindBuffer(gl::ARRAY_BUFFER, vertex_buffer_ID);
-
-    gl::BufferData(gl::ARRAY_BUFFER, byte_size_of_array(vertices), pointer_to_array(vertices), gl::STATIC_DRAW);
-
-    let vertex_attrib_ptr_idx = 0;
-    let components_per_vertex = 3;
-    let stride: i32 = 0;         //Set to 0 because input only contains vertices, and thus OpenGL can figure it out by itself
-    let first_data = ptr::null();
-    gl::VertexAttribPointer(vertex_attrib_ptr_idx, components_per_vertex, 
-                            gl::FLOAT, gl::FALSE, stride, first_data);
-
-    gl::EnableVertexAttribArray(vertex_attrib_ptr_idx);
-
-    /* Vertex Buffer Object for colours */
-
-    let mut colour_buffer_ID: u32 = 0;
-    let number_of_CVBOs = 1;
-
-    gl::GenBuffers(number_of_CVBOs, &mut colour_buffer_ID as *mut u32);
-    gl::BindBuffer(gl::ARRAY_BUFFER, colour_buffer_ID);
-
-    gl::BufferData(gl::ARRAY_BUFFER, byte_size_of_array(colour), pointer_to_array(colour), gl::STATIC_DRAW);
-
-    let colour_attrib_ptr_idx = 1;
-    let components_per_colour = 4;
-
-    gl::VertexAttribPointer(colour_attrib_ptr_idx, components_per_colour,
-                            gl::FLOAT, gl::FALSE, stride, first_data);
-    
-    gl::EnableVertexAttribArray(colour_attrib_ptr_idx);
-
-    /* Vertex Buffer Object for indices */
-    let mut idx_buffer_ID: u32 = 0;
-    let number_of_IVBOs = 1;
-
-    gl::GenBuffers(number_of_IVBOs, &mut idx_buffer_ID as *mut u32);
-    gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, idx_buffer_ID);
-
-    gl::BufferData(gl::ELEMENT_ARRAY_BUFFER, byte_size_of_array(indices), pointer_to_array(indices), gl::STATIC_DRAW);
-
-    return array_ID;
-} 
-
 fn main() {
     // Set up the necessary objects to deal with windows and event handling
     let el = glutin::event_loop::EventLoop::new();
@@ -147,7 +92,6 @@ fn main() {
         }
 
         // == // Set up your VAO here
-        let vao_num;
         
         let vertices: Vec<f32> = vec![-0.6, -0.2, -1.4, 0.2, 0.0, -1.4, -0.6, 0.2, -1.4,
                                       -0.2, 0.0, -1.2, 0.6, -0.2, -1.2, 0.6, 0.2, -1.2,
@@ -159,9 +103,14 @@ fn main() {
                                      0.0, 0.0, 1.0, 0.5, 0.0, 0.0, 1.0, 0.5, 0.0, 0.0, 1.0, 0.5,
                                      0.0, 1.0, 0.0, 0.5, 0.0, 1.0, 0.0, 0.5, 0.0, 1.0, 0.0, 0.5];
 
-        unsafe {
-            vao_num = set_up_VAO(&vertices, &colours, &indices);
-        }
+        // The vertex array owns its buffers, and deletes them along with itself
+        let vao = unsafe {
+            let mut vao = gpu::VertexArray::new();
+            vao.add_buffer(&vertices, 0, 3);
+            vao.add_buffer(&colours, 1, 4);
+            vao.set_indices(&indices);
+            vao
+        };
 
         // Basic usage of shader helper:
         // The example code below returns a shader object, which contains the field `.program_id`.
@@ -180,8 +129,11 @@ fn main() {
             program.activate();
         }
 
-        let mut angles: Vec<f32> = vec![0.0, 0.0];
-        let mut position: Vec<f32> = vec![0.0, 0.0, 0.0];
+        // The arrow keys turn the camera and WASD, Space and LShift move it along the world's axes
+        let mut camera = camera::Camera::perspective(1.0, 1.0, 100.0);
+        let size = context.window().inner_size();
+        camera.set_viewport(size.width, size.height);
+        let (mut yaw, mut pitch) = (0.0, 0.0);
         
 
         // Used to demonstrate keyboard handling -- feel free to remove
@@ -201,34 +153,34 @@ fn main() {
                 for key in keys.iter() {
                     match key {
                         VirtualKeyCode::Space => {
-                            position[1] += delta_time*2.0;
+                            camera.position.y += delta_time*2.0;
                         },
                         VirtualKeyCode::A => {
-                            position[0] -= delta_time*2.0;
+                            camera.position.x -= delta_time*2.0;
                         },
                         VirtualKeyCode::LShift => {
-                            position[1] -= delta_time*2.0;
+                            camera.position.y -= delta_time*2.0;
                         },
                         VirtualKeyCode::D => {
-                            position[0] += delta_time*2.0;
+                            camera.position.x += delta_time*2.0;
                         },
                         VirtualKeyCode::S => {
-                            position[2] += delta_time*2.0;
+                            camera.position.z += delta_time*2.0;
                         },
                         VirtualKeyCode::W => {
-                            position[2] -= delta_time*2.0;
+                            camera.position.z -= delta_time*2.0;
                         },
                         VirtualKeyCode::Up => {
-                            angles[0] += delta_time*0.5;
+                            pitch += delta_time*0.5;
                         },
                         VirtualKeyCode::Down => {
-                            angles[0] -= delta_time*0.5;
+                            pitch -= delta_time*0.5;
                         },
                         VirtualKeyCode::Left => {
-                            angles[1] += delta_time*0.5;
+                            yaw += delta_time*0.5;
                         },
                         VirtualKeyCode::Right => {
-                            angles[1] -= delta_time*0.5;
+                            yaw -= delta_time*0.5;
                         },
                         
 
@@ -250,22 +202,13 @@ fn main() {
                 gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
 
                 // Issue the necessary commands to draw your scene here
-                let translation_mat: glm::Mat4 = glm::translation(&glm::vec3(-position[0], -position[1], -position[2]));
-                let rot_1: glm::Mat4 = glm::rotation(-angles[0], &glm::vec3(1.0, 0.0, 0.0));
-                let rot_2: glm::Mat4 = glm::rotation(-angles[1], &glm::vec3(0.0, 1.0, 0.0));
-
-                let perspective_mat: glm::Mat4 = glm::perspective(0.75, 1.0, 1.0, 100.0);
-
-                let transformation = perspective_mat*rot_2*rot_1*translation_mat;
+                camera.set_yaw_pitch(yaw, pitch);
+                let transformation = camera.view_projection();
 
                 gl::UniformMatrix4fv(2, 1, 0, transformation.as_ptr());
 
 
-                let elements_to_draw: i32 = indices.len() as i32;
-                let zero_address = ptr::null();
-                gl::BindVertexArray(vao_num);
-                
-                gl::DrawElements(gl::TRIANGLES, elements_to_draw, gl::UNSIGNED_INT, zero_address);
+                vao.draw();
 
             }
 
@@ -0,0 +1,9 @@
+#pragma once
+
+// Everything which is the same for every draw call in a frame. Filled once per frame from
+// FrameUniforms in renderer.rs, which has to be kept in the same order.
+layout(std140) uniform Frame {
+    mat4 view_projection;
+    vec3 light_direction;            // The direction the light travels in, normalized
+    float time;                      // Seconds since the start
+};
@@ -1,10 +1,12 @@
 #version 430 core
 
+#include "frame.glsl"
+
 in layout(location=0) vec3 position;
 in layout(location=1) vec4 colour_in;
 in layout(location=3) vec3 normal_in;
-uniform layout(location=2) mat4 M_Mod;
-uniform layout(location=4) mat4 M_WP;
+uniform mat4 M_Mod;
+uniform mat3 M_Normal;
 
 out layout(location=1) vec4 colour_out;
 out layout(location=3) vec3 normal_out;
@@ -12,10 +14,19 @@ out layout(location=3) vec3 normal_out;
 
 void main()
 {
+    // When compiled for instanced drawing, the renderer declares the per-instance matrices
+    // instance_model and instance_normal in front of this file, and defines INSTANCED
+#ifdef INSTANCED
+    mat4 model = instance_model;
+    mat3 normal_matrix = instance_normal;
+#else
+    mat4 model = M_Mod;
+    mat3 normal_matrix = M_Normal;
+#endif
 
     vec4 hom_pos = vec4(position.x, position.y, position.z, 1);
     
     colour_out = colour_in;
-    normal_out = normalize(mat3(M_Mod)*normal_in);
-    gl_Position = M_WP*M_Mod*hom_pos;
-}
\ No newline at end of file
+    normal_out = normalize(normal_matrix*normal_in);
+    gl_Position = view_projection*model*hom_pos;
+}
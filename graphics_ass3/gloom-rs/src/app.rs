@@ -0,0 +1,276 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::HashSet;
+
+use glutin::event::VirtualKeyCode;
+
+use crate::assets::MeshRegistry;
+use crate::camera::Camera;
+use crate::camera_controller::{ChaseController, FlyController, OrbitController};
+use crate::debug_output;
+use crate::program_cache::ProgramCache;
+use crate::renderer::{self, FrameUniforms, Renderer};
+use crate::scene_file::SceneFile;
+use crate::scene_graph::{NodeId, SceneGraph, SceneNode};
+use crate::shader::{Shader, ShaderBuilder, ShaderError};
+use crate::uniform_buffer::UniformBuffer;
+
+// Which controller steers the camera. C switches between them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CameraMode {
+    Fly,
+    Orbit,                           // Around the target helicopter
+    Chase,                           // Behind the target helicopter
+}
+
+impl CameraMode {
+    fn next(self) -> CameraMode {
+        match self {
+            CameraMode::Fly   => CameraMode::Orbit,
+            CameraMode::Orbit => CameraMode::Chase,
+            CameraMode::Chase => CameraMode::Fly,
+        }
+    }
+}
+
+// Everything the application draws, and how it changes from frame to frame. It does not know
+// where it is drawn to, so the window in main and the headless renderer can share it. Needs a
+// current OpenGL context for as long as it lives.
+pub struct App {
+    pub scene         : SceneGraph,
+    pub camera        : Camera,
+    pub camera_mode   : CameraMode,
+    pub fly           : FlyController,
+    pub orbit         : OrbitController,
+    pub chase         : ChaseController,
+    targets           : Vec<NodeId>,     // The helicopters the orbit and chase cameras can follow
+    target            : usize,           // Which of them they follow
+    last_update       : f32,             // The time of the previous update, for the chase camera's spring
+    meshes            : MeshRegistry,
+    program           : Shader,
+    instanced_program : Shader,      // The same shader, reading the matrices from the instance buffer
+    renderer          : Renderer,
+    frame_uniforms    : UniformBuffer<FrameUniforms>,
+    reported_errors   : HashSet<String>,   // Uniform errors would otherwise be printed every frame
+    animated          : Vec<NodeId>,
+}
+
+// Basic usage of shader helper:
+// The example code below returns a shader object, which contains the field `.program_id`.
+// The snippet is not enough to do the assignment, and will need to be modified (outside of
+// just using the correct path), but it only needs to be called once
+//
+//     shader::ShaderBuilder::new()
+//        .attach_file("./path/to/shader.file")?
+//        .link()?;
+//
+// Every step returns a Result with a ShaderError, which holds the driver's log if the
+// shader fails to compile or link.
+unsafe fn build_programs(cache: Option<&ProgramCache>) -> Result<(Shader, Shader), ShaderError> {
+    let builder = || match cache {
+        Some(cache) => ShaderBuilder::new().cache(cache),
+        None        => ShaderBuilder::new(),
+    };
+    let program = builder()
+        .attach_file("shaders/simple.vert")?
+        .attach_file("shaders/simple.frag")?
+        .link()?;
+    let instanced_program = builder()
+        .attach_file_with_prelude("shaders/simple.vert", &renderer::instancing_prelude())?
+        .attach_file("shaders/simple.frag")?
+        .link()?;
+    Ok((program, instanced_program))
+}
+
+impl App {
+    // Sets up the OpenGL state, uploads the scene and builds the shaders. Programs are looked
+    // up in the cache if one is given.
+    pub unsafe fn new(scene_path: &str, cache: Option<&ProgramCache>) -> Result<App, String> {
+        gl::Enable(gl::DEPTH_TEST);
+        gl::DepthFunc(gl::LESS);
+        gl::Enable(gl::CULL_FACE);
+        gl::Disable(gl::MULTISAMPLE);
+        gl::Enable(gl::BLEND);
+        gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+
+        // == // Set up your VAO here
+        let scene_file = SceneFile::load(scene_path)
+            .map_err(|e| format!("Failed to load {}: {}", scene_path, e))?;
+
+        // Every mesh is only uploaded once, no matter how many nodes draw it
+        let mut meshes = MeshRegistry::new();
+        let mut scene = SceneGraph::new();
+        let root = scene.root();
+        scene_file.build(&mut scene, root, |description| {
+            let gpu_mesh = meshes.load(&description.path, description.object.as_deref(), description.colour)?;
+            Ok(SceneNode::from_mesh(&gpu_mesh))
+        }).map_err(|e| format!("Failed to build {}: {}", scene_path, e))?;
+
+        let (mut program, mut instanced_program) = build_programs(cache).map_err(|e| e.to_string())?;
+
+        // The camera and the light are uploaded once per frame, to a buffer both programs read
+        let frame_uniforms = UniformBuffer::<FrameUniforms>::new(renderer::FRAME_BINDING);
+        debug_output::label(debug_output::Object::Buffer, frame_uniforms.id(), "Frame uniforms");
+        for shader in [&mut program, &mut instanced_program] {
+            shader.bind_uniform_buffer("Frame", &frame_uniforms).map_err(|e| e.to_string())?;
+        }
+
+        // Look up the nodes which move by themselves once, instead of searching the graph every frame
+        let animated = scene.find_all(|node| node.animation.is_some());
+
+        // The orbit and chase cameras start out following the first helicopter, if there is one
+        let camera = Camera::perspective(1.0, 1.0, 1000.0);
+        let targets = scene.find_tagged("helicopter");
+        let mut orbit = OrbitController::new(glm::Vec3::zeros(), 40.0);
+        let mut chase = ChaseController::new(glm::vec3(0.0, 5.0, 25.0));
+        orbit.target = targets.first().copied();
+        chase.target = targets.first().copied();
+
+        Ok(App {
+            scene,
+            camera,
+            camera_mode: CameraMode::Fly,
+            fly: FlyController::new(&camera),
+            orbit,
+            chase,
+            targets,
+            target: 0,
+            last_update: 0.0,
+            meshes,
+            program,
+            instanced_program,
+            renderer: Renderer::new(),
+            frame_uniforms,
+            reported_errors: HashSet::new(),
+            animated,
+        })
+    }
+
+    pub fn meshes(&self) -> &MeshRegistry {
+        &self.meshes
+    }
+
+    // Pick up changes to the shader files while the program is running
+    pub fn watch_shaders(&mut self, enabled: bool) {
+        self.program.watch(enabled);
+        self.instanced_program.watch(enabled);
+    }
+
+    // Rebuild the shaders if their files were saved. A broken shader is reported, and the last
+    // working version is kept until the file is fixed
+    pub unsafe fn reload_shaders(&mut self) {
+        for shader in [&mut self.program, &mut self.instanced_program] {
+            match shader.reload_if_changed() {
+                Ok(true)  => println!("Reloaded {}", shader.paths().join(", ")),
+                Ok(false) => { },
+                Err(e)    => eprintln!("{}", e),
+            }
+        }
+    }
+
+    // The size of the framebuffer which is drawn to, so that the camera does not stretch the image
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.camera.set_viewport(width, height);
+    }
+
+    // Keys which are held down, every frame
+    pub fn handle_keys(&mut self, keys: &[VirtualKeyCode], delta_time: f32) {
+        match self.camera_mode {
+            CameraMode::Fly   => self.fly.handle_keys(&mut self.camera, keys, delta_time),
+            CameraMode::Orbit => self.orbit.handle_keys(&self.camera, keys, delta_time),
+            CameraMode::Chase => { },
+        }
+    }
+
+    // Keys which were just pressed, once per press
+    pub fn key_pressed(&mut self, key: VirtualKeyCode) {
+        match key {
+            VirtualKeyCode::C => self.set_camera_mode(self.camera_mode.next()),
+            VirtualKeyCode::N => self.cycle_target(1),
+            VirtualKeyCode::B => self.cycle_target(-1),
+            _ => { }
+        }
+    }
+
+    // How far the mouse moved since the last frame, in pixels
+    pub fn handle_mouse(&mut self, delta: (f32, f32)) {
+        match self.camera_mode {
+            CameraMode::Fly   => self.fly.mouse_moved(&mut self.camera, delta),
+            CameraMode::Orbit => self.orbit.mouse_moved(delta),
+            CameraMode::Chase => { },
+        }
+    }
+
+    pub fn set_camera_mode(&mut self, mode: CameraMode) {
+        self.camera_mode = mode;
+        match mode {
+            // Carry on looking the way the other camera did, rather than snapping back
+            CameraMode::Fly   => self.fly.look_from(&self.camera),
+            CameraMode::Orbit => self.orbit.update(&mut self.camera, &self.scene),
+            CameraMode::Chase => self.chase.snap(&mut self.camera, &self.scene),
+        }
+        println!("Camera: {:?}{}", mode, self.describe_target());
+    }
+
+    // Moves on to the next or previous helicopter. The chase camera glides over to it
+    fn cycle_target(&mut self, step: isize) {
+        if self.targets.is_empty() {
+            return;
+        }
+        let count = self.targets.len() as isize;
+        self.target = (self.target as isize + step).rem_euclid(count) as usize;
+        self.orbit.target = Some(self.targets[self.target]);
+        self.chase.target = Some(self.targets[self.target]);
+        if self.camera_mode != CameraMode::Fly {
+            println!("Camera: {:?}{}", self.camera_mode, self.describe_target());
+        }
+    }
+
+    fn describe_target(&self) -> String {
+        match (self.camera_mode, self.targets.get(self.target)) {
+            (CameraMode::Fly, _) | (_, None) => String::new(),
+            (_, Some(&id)) => format!(", following {}", self.scene[id].name),
+        }
+    }
+
+    // Moves everything to where it is `elapsed` seconds in
+    pub fn update(&mut self, elapsed: f32) {
+        // Let every node with an animation move by itself
+        for &id in &self.animated {
+            let node = &mut self.scene[id];
+            if let Some(animation) = node.animation {
+                animation.apply(node, elapsed);
+            }
+        }
+
+        //Update transformations
+        self.scene.update_transformations();
+
+        // The cameras which follow a node move after it has
+        let delta_time = (elapsed - self.last_update).max(0.0);
+        self.last_update = elapsed;
+        match self.camera_mode {
+            CameraMode::Fly   => { },
+            CameraMode::Orbit => self.orbit.update(&mut self.camera, &self.scene),
+            CameraMode::Chase => self.chase.update(&mut self.camera, &self.scene, delta_time),
+        }
+    }
+
+    // Draws a frame into whatever framebuffer is bound
+    pub unsafe fn draw(&mut self, elapsed: f32) {
+        gl::ClearColor(0.768_627_45, 0.713_725_5, 0.949_019_6, 1.0); // moon raker, full opacity
+        gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+
+        // Issue the necessary commands to draw your scene here
+        self.frame_uniforms.update(&FrameUniforms {
+            view_projection : self.camera.view_projection(),
+            light_direction : glm::normalize(&glm::vec3(0.8, -0.5, 0.6)),
+            time            : elapsed,
+        });
+        if let Err(e) = self.renderer.draw(&self.scene, &self.program, &self.instanced_program) {
+            if self.reported_errors.insert(e.to_string()) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+}
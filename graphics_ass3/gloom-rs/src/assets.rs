@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::debug_output;
+use crate::gpu::{GpuMesh, VertexArray};
+use crate::mesh;
+
+// Keeps track of every mesh that has been uploaded, so that asking for the same object in the same
+// OBJ file twice gives back the same GpuMesh instead of uploading it again.
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MeshKey {
+    pub path   : String,
+    pub object : Option<String>,
+}
+
+impl fmt::Display for MeshKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.object {
+            Some(object) => write!(f, "{}#{}", self.path, object),
+            None         => write!(f, "{}", self.path),
+        }
+    }
+}
+
+struct Entry {
+    mesh         : GpuMesh,
+    colour       : [f32; 4],
+    vertex_count : usize,
+}
+
+#[derive(Default)]
+pub struct MeshRegistry {
+    entries: BTreeMap<MeshKey, Entry>,
+}
+
+impl MeshRegistry {
+    pub fn new() -> MeshRegistry {
+        MeshRegistry::default()
+    }
+
+    // The colour is baked into the vertices, so the same object can not be shared in two colours
+    pub unsafe fn load(&mut self, path: &str, object: Option<&str>, colour: [f32; 4]) -> Result<GpuMesh, String> {
+        let key = MeshKey { path: path.to_string(), object: object.map(str::to_string) };
+        if let Some(entry) = self.entries.get(&key) {
+            if entry.colour != colour {
+                return Err(format!("{} is already loaded with the colour {:?}, not {:?}", key, entry.colour, colour));
+            }
+            return Ok(entry.mesh.clone());
+        }
+
+        let cpu_mesh = mesh::load_object(path, object, colour)?;
+        let gpu_mesh = GpuMesh::new(VertexArray::from_mesh(&cpu_mesh));
+        debug_output::label(debug_output::Object::VertexArray, gpu_mesh.vao_id(), &key.to_string());
+        self.entries.insert(key, Entry { mesh: gpu_mesh.clone(), colour, vertex_count: cpu_mesh.vertex_count() });
+        Ok(gpu_mesh)
+    }
+
+    pub fn total_bytes(&self) -> isize {
+        self.entries.values().map(|entry| entry.mesh.size_bytes()).sum()
+    }
+}
+
+// One line per mesh with its size and the number of users, and the total at the bottom
+impl fmt::Display for MeshRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<48} {:>9} {:>10} {:>6}", "mesh", "vertices", "bytes", "users")?;
+        for (key, entry) in &self.entries {
+            writeln!(f, "{:<48} {:>9} {:>10} {:>6}",
+                key.to_string(), entry.vertex_count, entry.mesh.size_bytes(), entry.mesh.handle_count() - 1)?;
+        }
+        write!(f, "{} meshes, {:.2} MiB in total", self.entries.len(), self.total_bytes() as f64 / (1024.0 * 1024.0))
+    }
+}
@@ -0,0 +1,74 @@
+use std::time::{Duration, Instant};
+
+use crate::gpu::VertexArray;
+use crate::headless::HeadlessContext;
+use crate::mesh::{self, Mesh};
+use crate::scene_file::SceneFile;
+use crate::util;
+
+// Compares the interleaved and the split vertex layout, by uploading every mesh in a scene file
+// a number of times with each of them. Run it with
+//
+//     cargo run --release -- --bench-upload [scenes/helicopters.ron]
+//
+// It needs an OpenGL context, but no window, so it makes one the way --headless does, and runs
+// without a display too.
+
+const ITERATIONS: u32 = 50;
+
+struct Measurement {
+    name         : &'static str,
+    buffers      : usize,
+    bytes        : isize,
+    upload_time  : Duration,
+}
+
+unsafe fn measure(name: &'static str, meshes: &[Mesh], upload: unsafe fn(&Mesh) -> VertexArray) -> Measurement {
+    let mut measurement = Measurement { name, buffers: 0, bytes: 0, upload_time: Duration::ZERO };
+    for iteration in 0..ITERATIONS {
+        let before = Instant::now();
+        let vertex_arrays: Vec<VertexArray> = meshes.iter().map(|m| upload(m)).collect();
+        // Make sure the driver has actually copied the data before stopping the clock
+        gl::Finish();
+        measurement.upload_time += before.elapsed();
+        if iteration == 0 {
+            measurement.buffers = vertex_arrays.iter().map(|v| v.buffer_count()).sum();
+            measurement.bytes = vertex_arrays.iter().map(|v| v.size_bytes()).sum();
+        }
+    }
+    measurement.upload_time /= ITERATIONS;
+    measurement
+}
+
+pub fn run(scene_path: &str) {
+    let scene_file = SceneFile::load(scene_path)
+        .unwrap_or_else(|e| panic!("Failed to load {}: {}", scene_path, e));
+    let meshes: Vec<Mesh> = scene_file.meshes.iter()
+        .map(|m| mesh::load_object(&m.path, m.object.as_deref(), m.colour).unwrap())
+        .collect();
+    let vertices: usize = meshes.iter().map(|m| m.vertex_count()).sum();
+    let cpu_split: usize = meshes.iter()
+        .map(|m| (m.vertices.len() + m.colors.len() + m.normals.len()) * 4)
+        .sum();
+    let cpu_interleaved: usize = meshes.iter()
+        .map(|m| std::mem::size_of_val(&m.interleaved()[..]))
+        .sum();
+
+    let _context = unsafe { HeadlessContext::new() }
+        .unwrap_or_else(|e| panic!("Failed to create a headless OpenGL context: {}", e));
+
+    let results = unsafe {
+        println!("{}: {}", util::get_gl_string(gl::VENDOR), util::get_gl_string(gl::RENDERER));
+        [
+            measure("split",       &meshes, VertexArray::from_mesh_split),
+            measure("interleaved", &meshes, VertexArray::from_mesh),
+        ]
+    };
+
+    println!("{} meshes, {} vertices, averaged over {} uploads", meshes.len(), vertices, ITERATIONS);
+    println!("CPU side vertex data: split {} bytes, interleaved {} bytes", cpu_split, cpu_interleaved);
+    println!("{:<12} {:>8} {:>12} {:>12}", "layout", "buffers", "GPU bytes", "upload ms");
+    for r in &results {
+        println!("{:<12} {:>8} {:>12} {:>12.3}", r.name, r.buffers, r.bytes, r.upload_time.as_secs_f64() * 1e3);
+    }
+}
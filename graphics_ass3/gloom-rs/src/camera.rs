@@ -0,0 +1,177 @@
+extern crate nalgebra_glm as glm;
+
+use crate::rotation;
+
+// Where the scene is seen from, and how it is projected onto the screen. The camera is placed in
+// the world like any other object, and the view matrix is the inverse of that placement, so moving
+// the camera no longer means moving the whole world the other way.
+//
+//     let mut camera = Camera::perspective(1.0, 1.0, 1000.0);
+//     camera.set_viewport(width, height);
+//     camera.look_at(&helicopter_position, &glm::vec3(0.0, 1.0, 0.0));
+//     let view_projection = camera.view_projection();
+
+// The application only looks through a perspective camera
+#[cfg_attr(not(test), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    Perspective { fov_y: f32 },      // The vertical field of view, in radians
+    Orthographic { height: f32 },    // How much of the world fits on screen vertically
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Camera {
+    pub position    : glm::Vec3,
+    pub orientation : glm::Quat,     // The camera looks down its own -Z axis, with +Y up
+    pub projection  : Projection,
+    pub near        : f32,
+    pub far         : f32,
+    pub aspect      : f32,           // Width over height of what is drawn to, see set_viewport
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl Camera {
+    pub fn perspective(fov_y: f32, near: f32, far: f32) -> Camera {
+        Camera {
+            position: glm::Vec3::zeros(),
+            orientation: glm::quat_identity(),
+            projection: Projection::Perspective { fov_y },
+            near,
+            far,
+            aspect: 4.0 / 3.0,
+        }
+    }
+
+    pub fn orthographic(height: f32, near: f32, far: f32) -> Camera {
+        Camera { projection: Projection::Orthographic { height }, ..Camera::perspective(1.0, near, far) }
+    }
+
+    // Matches the aspect ratio to the framebuffer, so that nothing is stretched
+    pub fn set_viewport(&mut self, width: u32, height: u32) {
+        if width > 0 && height > 0 {
+            self.aspect = width as f32 / height as f32;
+        }
+    }
+
+    // Which way the camera is looking, and its right and up directions, in world space
+    pub fn forward(&self) -> glm::Vec3 {
+        glm::quat_rotate_vec3(&self.orientation, &glm::vec3(0.0, 0.0, -1.0))
+    }
+
+    pub fn right(&self) -> glm::Vec3 {
+        glm::quat_rotate_vec3(&self.orientation, &glm::vec3(1.0, 0.0, 0.0))
+    }
+
+    pub fn up(&self) -> glm::Vec3 {
+        glm::quat_rotate_vec3(&self.orientation, &glm::vec3(0.0, 1.0, 0.0))
+    }
+
+    // Turn towards a point in the world. Nothing happens if the camera is already there
+    pub fn look_at(&mut self, target: &glm::Vec3, up: &glm::Vec3) {
+        let forward = target - self.position;
+        if glm::length2(&forward) > 0.0 {
+            self.orientation = rotation::look_rotation(&forward, up);
+        }
+    }
+
+    // From world space to the camera's own, which is the inverse of where the camera is placed:
+    // (T(position) * R(orientation))^-1 = R^T * T(-position)
+    pub fn view_matrix(&self) -> glm::Mat4 {
+        glm::quat_to_mat4(&glm::quat_conjugate(&self.orientation)) * glm::translation(&-self.position)
+    }
+
+    // From the camera's space to clip space, with depth mapped to [-1, 1] as OpenGL expects
+    pub fn projection_matrix(&self) -> glm::Mat4 {
+        match self.projection {
+            // Unlike GLM in C++, nalgebra-glm takes the aspect ratio before the field of view
+            Projection::Perspective { fov_y } => glm::perspective(self.aspect, fov_y, self.near, self.far),
+            Projection::Orthographic { height } => {
+                let (half_width, half_height) = (height * self.aspect / 2.0, height / 2.0);
+                glm::ortho(-half_width, half_width, -half_height, half_height, self.near, self.far)
+            },
+        }
+    }
+
+    pub fn view_projection(&self) -> glm::Mat4 {
+        self.projection_matrix() * self.view_matrix()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    fn assert_close(actual: &glm::Mat4, expected: &glm::Mat4) {
+        assert!((actual - expected).abs().max() < 1e-5, "{} != {}", actual, expected);
+    }
+
+    fn transform(matrix: &glm::Mat4, point: glm::Vec3) -> glm::Vec3 {
+        let v = matrix * glm::vec4(point.x, point.y, point.z, 1.0);
+        v.xyz() / v.w
+    }
+
+    #[test]
+    fn the_view_undoes_where_the_camera_is() {
+        let mut camera = Camera::perspective(1.0, 1.0, 100.0);
+        assert_close(&camera.view_matrix(), &glm::Mat4::identity());
+
+        camera.position = glm::vec3(1.0, 2.0, 3.0);
+        assert_close(&camera.view_matrix(), &glm::translation(&glm::vec3(-1.0, -2.0, -3.0)));
+
+        // Turned a quarter to the left, looking down -X
+        camera.orientation = rotation::from_axis_angle(&glm::vec3(0.0, 1.0, 0.0), FRAC_PI_2);
+        assert!(glm::distance(&camera.forward(), &glm::vec3(-1.0, 0.0, 0.0)) < 1e-5);
+        let ahead = transform(&camera.view_matrix(), glm::vec3(-4.0, 2.0, 3.0));
+        assert!(glm::distance(&ahead, &glm::vec3(0.0, 0.0, -5.0)) < 1e-5, "{}", ahead);
+        let right = transform(&camera.view_matrix(), camera.position + camera.right());
+        assert!(glm::distance(&right, &glm::vec3(1.0, 0.0, 0.0)) < 1e-5, "{}", right);
+    }
+
+    #[test]
+    fn looking_at_a_point_puts_it_straight_ahead() {
+        let mut camera = Camera::perspective(1.0, 1.0, 100.0);
+        camera.position = glm::vec3(10.0, 5.0, 0.0);
+        camera.look_at(&glm::vec3(0.0, 5.0, -10.0), &glm::vec3(0.0, 1.0, 0.0));
+        let target = transform(&camera.view_matrix(), glm::vec3(0.0, 5.0, -10.0));
+        assert!(glm::distance(&target, &glm::vec3(0.0, 0.0, -(200.0f32).sqrt())) < 1e-4, "{}", target);
+        assert!(glm::distance(&camera.up(), &glm::vec3(0.0, 1.0, 0.0)) < 1e-5);
+    }
+
+    #[test]
+    fn perspective_matches_the_textbook_matrix() {
+        // 90 degrees, twice as wide as high, near 1 and far 3
+        let mut camera = Camera::perspective(FRAC_PI_2, 1.0, 3.0);
+        camera.set_viewport(800, 400);
+        assert_close(&camera.projection_matrix(), &glm::mat4(
+            0.5, 0.0,  0.0,  0.0,
+            0.0, 1.0,  0.0,  0.0,
+            0.0, 0.0, -2.0, -3.0,
+            0.0, 0.0, -1.0,  0.0,
+        ));
+        // The near and far planes end up at the ends of the depth range
+        assert!((transform(&camera.projection_matrix(), glm::vec3(0.0, 0.0, -1.0)).z + 1.0).abs() < 1e-5);
+        assert!((transform(&camera.projection_matrix(), glm::vec3(0.0, 0.0, -3.0)).z - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn orthographic_matches_the_textbook_matrix() {
+        // 4 high, so 8 wide at 2:1, near 1 and far 3
+        let mut camera = Camera::orthographic(4.0, 1.0, 3.0);
+        camera.set_viewport(800, 400);
+        assert_close(&camera.projection_matrix(), &glm::mat4(
+            0.25, 0.0,  0.0,  0.0,
+            0.0,  0.5,  0.0,  0.0,
+            0.0,  0.0, -1.0, -2.0,
+            0.0,  0.0,  0.0,  1.0,
+        ));
+    }
+
+    #[test]
+    fn an_empty_viewport_keeps_the_aspect() {
+        let mut camera = Camera::perspective(1.0, 1.0, 100.0);
+        camera.set_viewport(1920, 1080);
+        camera.set_viewport(1920, 0);
+        assert_eq!(camera.aspect, 1920.0 / 1080.0);
+    }
+}
@@ -0,0 +1,414 @@
+extern crate nalgebra_glm as glm;
+
+use glutin::event::VirtualKeyCode;
+
+use crate::camera::Camera;
+use crate::rotation;
+use crate::scene_graph::{NodeId, SceneGraph};
+
+// Ways of steering a Camera from the keyboard and mouse. They keep their own state, like the
+// angles the camera is turned by, and write the result into the camera every time it changes.
+
+// Flies freely, looking around with the mouse:
+//
+//   * The mouse turns the camera, sideways about the world's up axis and up and down about the
+//     camera's own right axis, so the horizon never tilts
+//   * W and S move along the view direction, A and D sideways, Space and LShift up and down
+//   * Holding LControl moves faster, and LAlt slower
+//   * The arrow keys turn the camera like the mouse does
+pub struct FlyController {
+    pub sensitivity : f32,           // Radians turned per pixel the mouse moves
+    pub turn_speed  : f32,           // Radians per second when turning with the arrow keys
+    pub speed       : f32,           // Units per second
+    pub fast        : f32,           // Speed multiplier while LControl is held
+    pub slow        : f32,           // Speed multiplier while LAlt is held
+    pub max_pitch   : f32,           // How far up or down the camera can look, in radians
+    yaw             : f32,           // About the world's Y axis, zero looking down -Z
+    pitch           : f32,           // Positive looking up
+}
+
+impl FlyController {
+    // Starts out looking wherever the camera already is
+    pub fn new(camera: &Camera) -> FlyController {
+        let mut controller = FlyController {
+            sensitivity: 0.002,
+            turn_speed: 0.5,
+            speed: 40.0,
+            fast: 4.0,
+            slow: 0.25,
+            max_pitch: 89f32.to_radians(),
+            yaw: 0.0,
+            pitch: 0.0,
+        };
+        controller.look_from(camera);
+        controller
+    }
+
+    // Takes over the direction the camera looks in, after something else has moved it
+    pub fn look_from(&mut self, camera: &Camera) {
+        let forward = camera.forward();
+        self.yaw = (-forward.x).atan2(-forward.z);
+        self.pitch = forward.y.clamp(-1.0, 1.0).asin().clamp(-self.max_pitch, self.max_pitch);
+    }
+
+    // Turns by the given angles, keeping the pitch within max_pitch so the camera can not flip
+    // over the top
+    pub fn turn(&mut self, camera: &mut Camera, yaw: f32, pitch: f32) {
+        self.yaw = (self.yaw + yaw) % std::f32::consts::TAU;
+        self.pitch = (self.pitch + pitch).clamp(-self.max_pitch, self.max_pitch);
+        camera.orientation = rotation::from_axis_angle(&glm::vec3(0.0, 1.0, 0.0), self.yaw)
+            * rotation::from_axis_angle(&glm::vec3(1.0, 0.0, 0.0), self.pitch);
+    }
+
+    // The mouse movement since the last frame, in pixels. Moving the mouse right or down, which
+    // are the positive directions, turns the camera right or down.
+    pub fn mouse_moved(&mut self, camera: &mut Camera, delta: (f32, f32)) {
+        self.turn(camera, -delta.0 * self.sensitivity, -delta.1 * self.sensitivity);
+    }
+
+    pub fn handle_keys(&mut self, camera: &mut Camera, keys: &[VirtualKeyCode], delta_time: f32) {
+        let mut direction = glm::Vec3::zeros();
+        let mut speed = self.speed;
+        let (mut yaw, mut pitch) = (0.0, 0.0);
+        for key in keys {
+            match key {
+                VirtualKeyCode::W      => direction += camera.forward(),
+                VirtualKeyCode::S      => direction -= camera.forward(),
+                VirtualKeyCode::D      => direction += camera.right(),
+                VirtualKeyCode::A      => direction -= camera.right(),
+                VirtualKeyCode::Space  => direction += camera.up(),
+                VirtualKeyCode::LShift => direction -= camera.up(),
+                VirtualKeyCode::LControl => speed *= self.fast,
+                VirtualKeyCode::LAlt     => speed *= self.slow,
+                VirtualKeyCode::Left  => yaw += self.turn_speed,
+                VirtualKeyCode::Right => yaw -= self.turn_speed,
+                VirtualKeyCode::Up    => pitch += self.turn_speed,
+                VirtualKeyCode::Down  => pitch -= self.turn_speed,
+                _ => { }
+            }
+        }
+        // Moving diagonally is not any faster than moving straight
+        if glm::length2(&direction) > 1e-12 {
+            camera.position += glm::normalize(&direction) * speed * delta_time;
+        }
+        if yaw != 0.0 || pitch != 0.0 {
+            self.turn(camera, yaw * delta_time, pitch * delta_time);
+        }
+    }
+}
+
+// Circles around a point, or around a node as it moves, always looking at it:
+//
+//   * The mouse and the arrow keys move the camera around the point
+//   * W and S zoom in and out
+//   * A and D move the point sideways, Space and LShift up and down
+pub struct OrbitController {
+    pub target       : Option<NodeId>, // The node to circle around, if any
+    pub pivot        : glm::Vec3,      // The point circled around, relative to the target if there is one
+    pub distance     : f32,
+    pub min_distance : f32,
+    pub max_distance : f32,
+    pub sensitivity  : f32,            // Radians per pixel the mouse moves
+    pub turn_speed   : f32,            // Radians per second with the arrow keys
+    pub zoom_speed   : f32,            // How much closer per second, as a fraction of the distance
+    pub pan_speed    : f32,            // Also a fraction of the distance, so panning far away is quicker
+    pub max_pitch    : f32,
+    yaw              : f32,            // Zero puts the camera on the +Z side of the pivot
+    pitch            : f32,            // Positive puts the camera above the pivot
+}
+
+impl OrbitController {
+    pub fn new(pivot: glm::Vec3, distance: f32) -> OrbitController {
+        OrbitController {
+            target: None,
+            pivot,
+            distance,
+            min_distance: 2.0,
+            max_distance: 500.0,
+            sensitivity: 0.005,
+            turn_speed: 1.0,
+            zoom_speed: 1.0,
+            pan_speed: 0.5,
+            max_pitch: 89f32.to_radians(),
+            yaw: 0.0,
+            pitch: 0.3,
+        }
+    }
+
+    pub fn turn(&mut self, yaw: f32, pitch: f32) {
+        self.yaw = (self.yaw + yaw) % std::f32::consts::TAU;
+        self.pitch = (self.pitch + pitch).clamp(-self.max_pitch, self.max_pitch);
+    }
+
+    // Dragging the mouse turns the scene along with it, as if grabbing it
+    pub fn mouse_moved(&mut self, delta: (f32, f32)) {
+        self.turn(-delta.0 * self.sensitivity, delta.1 * self.sensitivity);
+    }
+
+    pub fn handle_keys(&mut self, camera: &Camera, keys: &[VirtualKeyCode], delta_time: f32) {
+        let mut pan = glm::Vec3::zeros();
+        let mut zoom = 0.0;
+        let (mut yaw, mut pitch) = (0.0, 0.0);
+        for key in keys {
+            match key {
+                VirtualKeyCode::W      => zoom += 1.0,
+                VirtualKeyCode::S      => zoom -= 1.0,
+                VirtualKeyCode::D      => pan += camera.right(),
+                VirtualKeyCode::A      => pan -= camera.right(),
+                VirtualKeyCode::Space  => pan += camera.up(),
+                VirtualKeyCode::LShift => pan -= camera.up(),
+                VirtualKeyCode::Left  => yaw -= self.turn_speed,
+                VirtualKeyCode::Right => yaw += self.turn_speed,
+                VirtualKeyCode::Up    => pitch += self.turn_speed,
+                VirtualKeyCode::Down  => pitch -= self.turn_speed,
+                _ => { }
+            }
+        }
+        // Zooming is multiplicative, so it slows down as the camera gets close
+        self.distance = (self.distance * (-zoom * self.zoom_speed * delta_time).exp())
+            .clamp(self.min_distance, self.max_distance);
+        if glm::length2(&pan) > 1e-12 {
+            self.pivot += glm::normalize(&pan) * self.pan_speed * self.distance * delta_time;
+        }
+        self.turn(yaw * delta_time, pitch * delta_time);
+    }
+
+    // The point looked at, in world space. Falls back to the pivot alone if the target is gone
+    pub fn focus(&self, scene: &SceneGraph) -> glm::Vec3 {
+        match self.target.and_then(|id| scene.get(id)) {
+            Some(node) => node.current_transformation_matrix.column(3).xyz() + self.pivot,
+            None       => self.pivot,
+        }
+    }
+
+    // Places the camera. Call it after the scene's transformations are updated for the frame
+    pub fn update(&mut self, camera: &mut Camera, scene: &SceneGraph) {
+        let focus = self.focus(scene);
+        let direction = glm::vec3(
+            self.pitch.cos() * self.yaw.sin(),
+            self.pitch.sin(),
+            self.pitch.cos() * self.yaw.cos(),
+        );
+        camera.position = focus + direction * self.distance;
+        camera.look_at(&focus, &glm::vec3(0.0, 1.0, 0.0));
+    }
+}
+
+// Follows behind a node, as if on a spring, looking at it. The spring lets the camera lag a little
+// when the node speeds up or turns, which makes the movement much easier to follow than a camera
+// which is nailed to the node. The offset is in the node's own axes, without its scale, so the
+// camera stays behind the node however it turns.
+pub struct ChaseController {
+    pub target      : Option<NodeId>,
+    pub offset      : glm::Vec3,     // Where the camera wants to be; the helicopters fly towards -Z
+    pub look_offset : glm::Vec3,     // Which point to look at, in the same axes
+    pub stiffness   : f32,           // How hard the spring pulls, per second squared
+    pub damping     : f32,           // 2 * sqrt(stiffness) settles fastest without overshooting
+    velocity        : glm::Vec3,
+}
+
+// Longer frames are simulated in steps of at most this, so the spring does not blow up on a hitch
+const MAX_SPRING_STEP: f32 = 1.0 / 120.0;
+
+impl ChaseController {
+    pub fn new(offset: glm::Vec3) -> ChaseController {
+        let stiffness = 30.0;
+        ChaseController {
+            target: None,
+            offset,
+            look_offset: glm::Vec3::zeros(),
+            stiffness,
+            damping: 2.0 * stiffness.sqrt(),
+            velocity: glm::Vec3::zeros(),
+        }
+    }
+
+    // A point given in the node's own axes, in world space. The axes are normalized, which
+    // takes the scale out of the node's matrix but keeps its rotation.
+    fn world_point(scene: &SceneGraph, id: NodeId, local: &glm::Vec3) -> Option<glm::Vec3> {
+        let matrix = &scene.get(id)?.current_transformation_matrix;
+        let axis = |i: usize| glm::normalize(&matrix.column(i).xyz());
+        Some(matrix.column(3).xyz() + axis(0) * local.x + axis(1) * local.y + axis(2) * local.z)
+    }
+
+    // Where the spring pulls the camera to
+    pub fn desired_position(&self, scene: &SceneGraph) -> Option<glm::Vec3> {
+        Self::world_point(scene, self.target?, &self.offset)
+    }
+
+    // Jumps straight to the desired position, like when the camera is first switched to
+    pub fn snap(&mut self, camera: &mut Camera, scene: &SceneGraph) {
+        if let Some(desired) = self.desired_position(scene) {
+            camera.position = desired;
+            self.velocity = glm::Vec3::zeros();
+        }
+        self.look(camera, scene);
+    }
+
+    // Moves the camera along with the target. Call it after the scene's transformations are
+    // updated for the frame. Without a target the camera stays where it is.
+    pub fn update(&mut self, camera: &mut Camera, scene: &SceneGraph, delta_time: f32) {
+        let desired = match self.desired_position(scene) {
+            Some(desired) => desired,
+            None          => return,
+        };
+        let mut remaining = delta_time.max(0.0);
+        while remaining > 0.0 {
+            let step = remaining.min(MAX_SPRING_STEP);
+            let acceleration = (desired - camera.position) * self.stiffness - self.velocity * self.damping;
+            self.velocity += acceleration * step;
+            camera.position += self.velocity * step;
+            remaining -= step;
+        }
+        self.look(camera, scene);
+    }
+
+    fn look(&self, camera: &mut Camera, scene: &SceneGraph) {
+        if let Some(focus) = self.target.and_then(|id| Self::world_point(scene, id, &self.look_offset)) {
+            camera.look_at(&focus, &glm::vec3(0.0, 1.0, 0.0));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene_graph::SceneNode;
+    use std::f32::consts::FRAC_PI_2;
+
+    // A node at (5, 0, 0), scaled up and turned to fly towards -X
+    fn scene_with_node() -> (SceneGraph, NodeId) {
+        let mut scene = SceneGraph::new();
+        let root = scene.root();
+        let id = scene.add_child(root, SceneNode::new()).unwrap();
+        scene[id].position = glm::vec3(5.0, 0.0, 0.0);
+        scene[id].scale = glm::vec3(3.0, 3.0, 3.0);
+        scene[id].orientation = rotation::from_axis_angle(&glm::vec3(0.0, 1.0, 0.0), FRAC_PI_2);
+        scene.update_transformations();
+        (scene, id)
+    }
+
+    fn assert_near(actual: glm::Vec3, expected: glm::Vec3) {
+        assert!(glm::distance(&actual, &expected) < 1e-4, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn the_mouse_turns_the_camera() {
+        let mut camera = Camera::perspective(1.0, 1.0, 100.0);
+        let mut controller = FlyController::new(&camera);
+        controller.sensitivity = 0.01;
+
+        // A quarter turn to the right
+        controller.mouse_moved(&mut camera, (100.0 * FRAC_PI_2, 0.0));
+        assert_near(camera.forward(), glm::vec3(1.0, 0.0, 0.0));
+        assert_near(camera.up(), glm::vec3(0.0, 1.0, 0.0));
+
+        // Moving the mouse up looks up, but never past max_pitch
+        controller.mouse_moved(&mut camera, (0.0, -1000.0));
+        assert_eq!(controller.pitch, controller.max_pitch);
+        assert!(camera.forward().y > 0.99);
+        assert!(camera.up().y > 0.0, "the camera flipped over");
+    }
+
+    #[test]
+    fn the_angles_start_from_the_camera() {
+        let mut camera = Camera::perspective(1.0, 1.0, 100.0);
+        camera.look_at(&glm::vec3(-1.0, 1.0, 0.0), &glm::vec3(0.0, 1.0, 0.0));
+        let controller = FlyController::new(&camera);
+        assert!((controller.yaw - FRAC_PI_2).abs() < 1e-5);
+        assert!((controller.pitch - std::f32::consts::FRAC_PI_4).abs() < 1e-5);
+    }
+
+    #[test]
+    fn keys_move_relative_to_the_view() {
+        let mut camera = Camera::perspective(1.0, 1.0, 100.0);
+        let mut controller = FlyController::new(&camera);
+        controller.turn(&mut camera, FRAC_PI_2, 0.0); // Looking down -X
+
+        controller.handle_keys(&mut camera, &[VirtualKeyCode::W], 0.5);
+        assert_near(camera.position, glm::vec3(-20.0, 0.0, 0.0));
+        controller.handle_keys(&mut camera, &[VirtualKeyCode::D, VirtualKeyCode::LControl], 0.5);
+        assert_near(camera.position, glm::vec3(-20.0, 0.0, -80.0));
+
+        // Diagonally, at the same speed, and slowly
+        camera.position = glm::Vec3::zeros();
+        controller.handle_keys(&mut camera, &[VirtualKeyCode::S, VirtualKeyCode::Space, VirtualKeyCode::LAlt], 1.0);
+        let step = 10.0 / 2f32.sqrt();
+        assert_near(camera.position, glm::vec3(step, step, 0.0));
+    }
+
+    #[test]
+    fn orbiting_circles_the_pivot_and_follows_the_target() {
+        let (mut scene, id) = scene_with_node();
+        let mut camera = Camera::perspective(1.0, 1.0, 100.0);
+        let mut orbit = OrbitController::new(glm::Vec3::zeros(), 10.0);
+        orbit.turn(0.0, -orbit.pitch);
+        orbit.update(&mut camera, &scene);
+        assert_near(camera.position, glm::vec3(0.0, 0.0, 10.0));
+        assert_near(camera.forward(), glm::vec3(0.0, 0.0, -1.0));
+
+        // A quarter of the way around, and the pivot moves with the target
+        orbit.turn(FRAC_PI_2, 0.0);
+        orbit.target = Some(id);
+        orbit.update(&mut camera, &scene);
+        assert_near(camera.position, glm::vec3(15.0, 0.0, 0.0));
+        scene[id].position.y = 2.0;
+        scene.update_transformations();
+        orbit.update(&mut camera, &scene);
+        assert_near(camera.position, glm::vec3(15.0, 2.0, 0.0));
+        assert_near(camera.forward(), glm::vec3(-1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn zooming_stays_within_bounds() {
+        let camera = Camera::perspective(1.0, 1.0, 100.0);
+        let mut orbit = OrbitController::new(glm::Vec3::zeros(), 10.0);
+        orbit.handle_keys(&camera, &[VirtualKeyCode::W], 1.0);
+        assert!(orbit.distance < 10.0);
+        orbit.handle_keys(&camera, &[VirtualKeyCode::W], 100.0);
+        assert_eq!(orbit.distance, orbit.min_distance);
+        orbit.handle_keys(&camera, &[VirtualKeyCode::S], 100.0);
+        assert_eq!(orbit.distance, orbit.max_distance);
+    }
+
+    #[test]
+    fn the_chase_offset_turns_with_the_node_but_does_not_scale() {
+        let (scene, id) = scene_with_node();
+        let mut chase = ChaseController::new(glm::vec3(0.0, 2.0, 10.0));
+        assert_eq!(chase.desired_position(&scene), None);
+        chase.target = Some(id);
+        // Behind the node is +X, now that it flies towards -X
+        assert_near(chase.desired_position(&scene).unwrap(), glm::vec3(15.0, 2.0, 0.0));
+
+        let mut camera = Camera::perspective(1.0, 1.0, 100.0);
+        chase.snap(&mut camera, &scene);
+        assert_near(camera.position, glm::vec3(15.0, 2.0, 0.0));
+        let to_node = glm::normalize(&(glm::vec3(5.0, 0.0, 0.0) - camera.position));
+        assert_near(camera.forward(), to_node);
+    }
+
+    #[test]
+    fn the_chase_spring_settles_without_overshooting() {
+        let (scene, id) = scene_with_node();
+        let mut chase = ChaseController::new(glm::vec3(0.0, 0.0, 10.0));
+        chase.target = Some(id);
+        let mut camera = Camera::perspective(1.0, 1.0, 100.0);
+        camera.position = glm::vec3(15.0, 0.0, 20.0);
+
+        let desired = chase.desired_position(&scene).unwrap();
+        let mut previous = glm::distance(&camera.position, &desired);
+        for _ in 0..60 {
+            chase.update(&mut camera, &scene, 1.0 / 30.0);
+            let distance = glm::distance(&camera.position, &desired);
+            assert!(distance <= previous + 1e-5, "the camera moved away, {} > {}", distance, previous);
+            previous = distance;
+        }
+        assert!(previous < 0.01, "still {} away after two seconds", previous);
+
+        // A long frame is no different from several short ones
+        camera.position = glm::vec3(15.0, 0.0, 20.0);
+        chase.velocity = glm::Vec3::zeros();
+        chase.update(&mut camera, &scene, 5.0);
+        assert_near(camera.position, desired);
+    }
+}
@@ -0,0 +1,92 @@
+use std::path::{Path, PathBuf};
+
+use crate::gpu;
+
+// Saving what was drawn as PNGs, for the report or to be made into a video. Both read from the
+// framebuffer which is bound for reading, so call them after drawing and before swapping the
+// buffers. A recording is just numbered frames in a directory, which ffmpeg turns into a video:
+//
+//     ffmpeg -framerate 60 -i recordings/recording_0001/frame_%05d.png helicopters.mp4
+
+// Saves the current frame, the right way up
+pub unsafe fn screenshot(width: u32, height: u32, path: &Path) -> image::ImageResult<()> {
+    gpu::read_pixels(width, height).save(path)
+}
+
+// The first of dir/prefix_0001.extension, dir/prefix_0002.extension and so on which does not
+// exist yet, so that nothing is overwritten. An empty extension gives a directory name.
+pub fn next_free_path(dir: &Path, prefix: &str, extension: &str) -> PathBuf {
+    (1..)
+        .map(|n| {
+            let name = format!("{}_{:04}", prefix, n);
+            let path = dir.join(name);
+            if extension.is_empty() { path } else { path.with_extension(extension) }
+        })
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+// Saves every frame it is given, and says how far time moves between them. The frames are
+// `step` seconds apart in the video whatever the time it took to draw them, so slow frames do
+// not make the animations jump.
+pub struct Recorder {
+    dir   : PathBuf,
+    step  : f32,                     // Simulated seconds between frames
+    frame : u32,                     // The number of the next frame
+}
+
+impl Recorder {
+    // Creates the directory if it is not there yet
+    pub fn new(dir: &Path, frames_per_second: f32) -> std::io::Result<Recorder> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Recorder { dir: dir.to_path_buf(), step: 1.0 / frames_per_second, frame: 0 })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn step(&self) -> f32 {
+        self.step
+    }
+
+    // How many frames have been saved
+    pub fn frames(&self) -> u32 {
+        self.frame
+    }
+
+    pub fn frame_path(&self, frame: u32) -> PathBuf {
+        self.dir.join(format!("frame_{:05}.png", frame))
+    }
+
+    pub unsafe fn capture(&mut self, width: u32, height: u32) -> image::ImageResult<PathBuf> {
+        let path = self.frame_path(self.frame);
+        screenshot(width, height, &path)?;
+        self.frame += 1;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_paths_skip_the_ones_taken() {
+        let dir = Path::new("target/capture_test");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir.join("recording_0001")).unwrap();
+        std::fs::write(dir.join("screenshot_0001.png"), b"").unwrap();
+        std::fs::write(dir.join("screenshot_0002.png"), b"").unwrap();
+        assert_eq!(next_free_path(dir, "screenshot", "png"), dir.join("screenshot_0003.png"));
+        assert_eq!(next_free_path(dir, "recording", ""), dir.join("recording_0002"));
+    }
+
+    #[test]
+    fn frames_are_numbered_from_zero_at_a_fixed_step() {
+        let recorder = Recorder::new(Path::new("target/capture_frames_test"), 50.0).unwrap();
+        assert_eq!(recorder.step(), 0.02);
+        assert_eq!(recorder.frames(), 0);
+        assert_eq!(recorder.frame_path(12), Path::new("target/capture_frames_test/frame_00012.png"));
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::os::raw::c_void;
+use std::sync::{Arc, Mutex, PoisonError};
+
+// Receives the messages the driver sends through KHR_debug, filters them, and passes them on to
+// wherever they should be logged. Install it once the context is current:
+//
+//     let _debug_output = DebugOutput::new()
+//         .min_severity(Severity::Low)
+//         .suppress(131185)                 // NVIDIA telling us where a buffer lives
+//         .sink(Sink::Stdout)
+//         .install();
+//
+// The messages stop when the returned guard is dropped. Nothing in here may panic while the driver
+// is calling it, since a panic can not unwind through the driver and aborts the process instead.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Notification,
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MessageType {
+    Error,
+    DeprecatedBehavior,
+    UndefinedBehavior,
+    Portability,
+    Performance,
+    Marker,
+    PushGroup,
+    PopGroup,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Api,
+    WindowSystem,
+    ShaderCompiler,
+    ThirdParty,
+    Application,
+    Other,
+}
+
+impl From<gl::types::GLenum> for Severity {
+    fn from(severity: gl::types::GLenum) -> Self {
+        match severity {
+            gl::DEBUG_SEVERITY_HIGH   => Severity::High,
+            gl::DEBUG_SEVERITY_MEDIUM => Severity::Medium,
+            gl::DEBUG_SEVERITY_LOW    => Severity::Low,
+            _                         => Severity::Notification,
+        }
+    }
+}
+
+impl From<gl::types::GLenum> for MessageType {
+    fn from(kind: gl::types::GLenum) -> Self {
+        match kind {
+            gl::DEBUG_TYPE_ERROR               => MessageType::Error,
+            gl::DEBUG_TYPE_DEPRECATED_BEHAVIOR => MessageType::DeprecatedBehavior,
+            gl::DEBUG_TYPE_UNDEFINED_BEHAVIOR  => MessageType::UndefinedBehavior,
+            gl::DEBUG_TYPE_PORTABILITY         => MessageType::Portability,
+            gl::DEBUG_TYPE_PERFORMANCE         => MessageType::Performance,
+            gl::DEBUG_TYPE_MARKER              => MessageType::Marker,
+            gl::DEBUG_TYPE_PUSH_GROUP          => MessageType::PushGroup,
+            gl::DEBUG_TYPE_POP_GROUP           => MessageType::PopGroup,
+            _                                  => MessageType::Other,
+        }
+    }
+}
+
+impl From<gl::types::GLenum> for Source {
+    fn from(source: gl::types::GLenum) -> Self {
+        match source {
+            gl::DEBUG_SOURCE_API             => Source::Api,
+            gl::DEBUG_SOURCE_WINDOW_SYSTEM   => Source::WindowSystem,
+            gl::DEBUG_SOURCE_SHADER_COMPILER => Source::ShaderCompiler,
+            gl::DEBUG_SOURCE_THIRD_PARTY     => Source::ThirdParty,
+            gl::DEBUG_SOURCE_APPLICATION     => Source::Application,
+            _                                => Source::Other,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub source   : Source,
+    pub kind     : MessageType,
+    pub id       : u32,
+    pub severity : Severity,
+    pub text     : String,
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[GL {:?} {:?} from {:?}, id {}] {}", self.severity, self.kind, self.source, self.id, self.text.trim_end())
+    }
+}
+
+impl std::error::Error for Message {}
+
+// The last `capacity` messages, for looking at after the fact, like in a test or an overlay.
+// Clones share the same messages.
+#[derive(Clone, Debug)]
+pub struct RingBuffer {
+    messages : Arc<Mutex<VecDeque<Message>>>,
+    capacity : usize,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl RingBuffer {
+    pub fn new(capacity: usize) -> RingBuffer {
+        RingBuffer { messages: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))), capacity }
+    }
+
+    // Called from the driver's callback, where a panic would abort the process, so a lock which
+    // was poisoned by a panicking reader is still used
+    fn push(&self, message: Message) {
+        let mut messages = self.messages.lock().unwrap_or_else(PoisonError::into_inner);
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        if self.capacity > 0 {
+            messages.push_back(message);
+        }
+    }
+
+    // Oldest first
+    pub fn messages(&self) -> Vec<Message> {
+        self.messages.lock().unwrap_or_else(PoisonError::into_inner).iter().cloned().collect()
+    }
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+pub enum Sink {
+    Stdout,
+    File(LineWriter<File>),
+    Ring(RingBuffer),
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl Sink {
+    // Appends to the file, creating it if needed
+    pub fn file(path: &str) -> std::io::Result<Sink> {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Sink::File(LineWriter::new(file)))
+    }
+
+    fn write(&mut self, message: &Message) {
+        match self {
+            // Losing a log line is not worth crashing over
+            Sink::Stdout     => { let _ = writeln!(std::io::stdout(), "{}", message); },
+            Sink::File(file) => { let _ = writeln!(file, "{}", message); },
+            Sink::Ring(ring) => ring.push(message.clone()),
+        }
+    }
+}
+
+pub struct DebugOutput {
+    min_severity   : Severity,
+    types          : Option<HashSet<MessageType>>,   // None lets every type through
+    suppressed     : HashSet<u32>,                   // Message ids which are never logged
+    sinks          : Vec<Sink>,
+    panic_on_error : bool,
+    first_error    : Option<Message>,                // Kept for the guard to raise, see panic_on_error
+}
+
+impl Default for DebugOutput {
+    fn default() -> DebugOutput {
+        DebugOutput {
+            min_severity: Severity::Low,
+            types: None,
+            suppressed: HashSet::new(),
+            sinks: vec![],
+            panic_on_error: false,
+            first_error: None,
+        }
+    }
+}
+
+// The builder methods take and give back the DebugOutput, so that they can be chained
+#[cfg_attr(not(test), allow(dead_code))]
+impl DebugOutput {
+    pub fn new() -> DebugOutput {
+        DebugOutput::default()
+    }
+
+    pub fn min_severity(mut self, severity: Severity) -> DebugOutput {
+        self.min_severity = severity;
+        self
+    }
+
+    // Only lets these types through. Calling it again adds to the list
+    pub fn only(mut self, kinds: &[MessageType]) -> DebugOutput {
+        self.types.get_or_insert_with(HashSet::new).extend(kinds);
+        self
+    }
+
+    pub fn suppress(mut self, id: u32) -> DebugOutput {
+        self.suppressed.insert(id);
+        self
+    }
+
+    pub fn sink(mut self, sink: Sink) -> DebugOutput {
+        self.sinks.push(sink);
+        self
+    }
+
+    // Makes the first error type message which is not suppressed, whatever its severity, fail the
+    // program on the Rust side: DebugOutputGuard::check gives it back, and dropping the guard
+    // without checking panics with it. Meant for tests.
+    pub fn panic_on_error(mut self, enabled: bool) -> DebugOutput {
+        self.panic_on_error = enabled;
+        self
+    }
+
+    fn accepts(&self, message: &Message) -> bool {
+        message.severity >= self.min_severity
+            && self.types.as_ref().is_none_or(|types| types.contains(&message.kind))
+            && !self.suppressed.contains(&message.id)
+    }
+
+    fn handle(&mut self, message: Message) {
+        if self.accepts(&message) {
+            for sink in &mut self.sinks {
+                sink.write(&message);
+            }
+        }
+        if self.panic_on_error && self.first_error.is_none()
+            && message.kind == MessageType::Error && !self.suppressed.contains(&message.id) {
+            self.first_error = Some(message);
+        }
+    }
+
+    // Starts receiving messages. Synchronous output is turned on, so that a message is sent
+    // while the call which caused it is still on the stack, for breakpoints and backtraces.
+    pub unsafe fn install(self) -> DebugOutputGuard {
+        let output = Box::new(Mutex::new(self));
+        gl::Enable(gl::DEBUG_OUTPUT);
+        gl::Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
+        gl::DebugMessageCallback(Some(callback), &*output as *const Mutex<DebugOutput> as *const c_void);
+        DebugOutputGuard { output }
+    }
+}
+
+// Keeps the DebugOutput alive for as long as the driver may call it
+pub struct DebugOutputGuard {
+    output: Box<Mutex<DebugOutput>>,
+}
+
+impl DebugOutputGuard {
+    // The first error the driver sent since the last check, when panic_on_error is on
+    pub fn check(&self) -> Result<(), Message> {
+        match self.output.lock().unwrap_or_else(PoisonError::into_inner).first_error.take() {
+            Some(message) => Err(message),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for DebugOutputGuard {
+    fn drop(&mut self) {
+        unsafe { gl::DebugMessageCallback(None, std::ptr::null()) };
+        // Panicking again while unwinding would abort, and the first panic says more anyway
+        if let Err(message) = self.check() {
+            if !std::thread::panicking() {
+                panic!("{}", message);
+            }
+        }
+    }
+}
+
+extern "system" fn callback(
+    source: u32, kind: u32, id: u32,
+    severity: u32, length: i32,
+    text: *const gl::types::GLchar, output: *mut c_void
+) {
+    // The text belongs to the driver, and is only borrowed for the duration of the call
+    let text = unsafe {
+        if length >= 0 {
+            String::from_utf8_lossy(std::slice::from_raw_parts(text as *const u8, length as usize)).into_owned()
+        } else {
+            std::ffi::CStr::from_ptr(text).to_string_lossy().into_owned()
+        }
+    };
+    let message = Message { source: source.into(), kind: kind.into(), id, severity: severity.into(), text };
+    let output = unsafe { &*(output as *const Mutex<DebugOutput>) };
+    // A poisoned lock means a sink panicked already, and there is nobody left to tell
+    if let Ok(mut output) = output.lock() {
+        output.handle(message);
+    }
+}
+
+// What kind of object a label is for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Object {
+    Buffer,
+    Shader,
+    Program,
+    VertexArray,
+    Texture,
+    Framebuffer,
+}
+
+impl From<Object> for gl::types::GLenum {
+    fn from(object: Object) -> Self {
+        match object {
+            Object::Buffer      => gl::BUFFER,
+            Object::Shader      => gl::SHADER,
+            Object::Program     => gl::PROGRAM,
+            Object::VertexArray => gl::VERTEX_ARRAY,
+            Object::Texture     => gl::TEXTURE,
+            Object::Framebuffer => gl::FRAMEBUFFER,
+        }
+    }
+}
+
+// Names an object in the driver's messages, and in tools like RenderDoc
+pub unsafe fn label(object: Object, id: u32, name: &str) {
+    gl::ObjectLabel(object.into(), id, name.len() as i32, name.as_ptr() as *const gl::types::GLchar);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::{self, HeadlessContext};
+
+    fn message(kind: MessageType, id: u32, severity: Severity) -> Message {
+        Message { source: Source::Api, kind, id, severity, text: "Something happened\n".to_string() }
+    }
+
+    #[test]
+    fn messages_are_filtered_by_severity_type_and_id() {
+        let output = DebugOutput::new()
+            .min_severity(Severity::Medium)
+            .only(&[MessageType::Error, MessageType::UndefinedBehavior])
+            .suppress(1282);
+        assert!(output.accepts(&message(MessageType::Error, 1, Severity::High)));
+        assert!(output.accepts(&message(MessageType::UndefinedBehavior, 1, Severity::Medium)));
+        assert!(!output.accepts(&message(MessageType::Error, 1, Severity::Low)));
+        assert!(!output.accepts(&message(MessageType::Performance, 1, Severity::High)));
+        assert!(!output.accepts(&message(MessageType::Error, 1282, Severity::High)));
+    }
+
+    #[test]
+    fn the_ring_buffer_keeps_the_latest_messages() {
+        let ring = RingBuffer::new(2);
+        let mut output = DebugOutput::new().sink(Sink::Ring(ring.clone()));
+        for id in 1..=3 {
+            output.handle(message(MessageType::Error, id, Severity::High));
+        }
+        output.handle(message(MessageType::Other, 4, Severity::Notification));
+        let ids: Vec<u32> = ring.messages().iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(ring.messages()[1].to_string(), "[GL High Error from Api, id 3] Something happened");
+
+        // Somebody panicked while holding the lock, which must not take the callback down with it
+        let poisoner = ring.clone();
+        let _ = std::thread::spawn(move || {
+            let _messages = poisoner.messages.lock().unwrap();
+            panic!("poisoned");
+        }).join();
+        output.handle(message(MessageType::Error, 5, Severity::High));
+        assert_eq!(ring.messages().last().map(|m| m.id), Some(5));
+    }
+
+    #[test]
+    fn file_sinks_append_a_line_per_message() {
+        let path = std::env::temp_dir().join(format!("gloom-rs-gl-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        for id in 1..=2 {
+            let mut output = DebugOutput::new().sink(Sink::file(path.to_str().unwrap()).unwrap());
+            output.handle(message(MessageType::Error, id, Severity::High));
+        }
+        let log = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(log, "[GL High Error from Api, id 1] Something happened\n[GL High Error from Api, id 2] Something happened\n");
+    }
+
+    #[test]
+    fn the_first_error_is_kept_when_asked_to() {
+        let mut output = DebugOutput::new().min_severity(Severity::High).panic_on_error(true).suppress(5);
+        output.handle(message(MessageType::Error, 5, Severity::High));
+        output.handle(message(MessageType::Performance, 6, Severity::High));
+        output.handle(message(MessageType::Error, 7, Severity::Low));
+        output.handle(message(MessageType::Error, 8, Severity::High));
+        assert_eq!(output.first_error.map(|m| m.id), Some(7));
+
+        let mut output = DebugOutput::new();
+        output.handle(message(MessageType::Error, 7, Severity::High));
+        assert_eq!(output.first_error, None);
+    }
+
+    #[test]
+    fn errors_are_raised_by_the_guard_and_not_the_callback() {
+        let _context = match headless::unless_skipped("errors_are_raised_by_the_guard_and_not_the_callback", unsafe { HeadlessContext::new() }) {
+            Some(context) => context,
+            None => return,
+        };
+        let guard = unsafe { DebugOutput::new().panic_on_error(true).install() };
+        assert!(guard.check().is_ok());
+        // Binding a buffer name which was never generated is an error in the core profile
+        unsafe { gl::BindBuffer(gl::ARRAY_BUFFER, 12345) };
+        let error = guard.check().unwrap_err();
+        assert_eq!(error.kind, MessageType::Error);
+        assert!(guard.check().is_ok());
+
+        unsafe { gl::BindBuffer(gl::ARRAY_BUFFER, 12345) };
+        let dropped = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(guard)));
+        assert!(dropped.is_err());
+    }
+}
@@ -0,0 +1,460 @@
+use std::rc::{Rc, Weak};
+
+use crate::mesh::{Mesh, Vertex};
+use crate::{byte_size_of_array, offset, pointer_to_array};
+
+// Owning wrappers around OpenGL buffer and vertex array names. They are deleted when dropped,
+// which means they have to be dropped on the thread that owns the OpenGL context.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttributeType {
+    Byte,
+    UnsignedByte,
+    Short,
+    UnsignedShort,
+    Int,
+    UnsignedInt,
+    HalfFloat,
+    Float,
+}
+
+impl AttributeType {
+    pub fn size_in_bytes(self) -> u32 {
+        match self {
+            AttributeType::Byte | AttributeType::UnsignedByte   => 1,
+            AttributeType::Short | AttributeType::UnsignedShort => 2,
+            AttributeType::HalfFloat                            => 2,
+            AttributeType::Int | AttributeType::UnsignedInt     => 4,
+            AttributeType::Float                                => 4,
+        }
+    }
+}
+
+impl From<AttributeType> for gl::types::GLenum {
+    fn from(kind: AttributeType) -> Self {
+        match kind {
+            AttributeType::Byte          => gl::BYTE,
+            AttributeType::UnsignedByte  => gl::UNSIGNED_BYTE,
+            AttributeType::Short         => gl::SHORT,
+            AttributeType::UnsignedShort => gl::UNSIGNED_SHORT,
+            AttributeType::Int           => gl::INT,
+            AttributeType::UnsignedInt   => gl::UNSIGNED_INT,
+            AttributeType::HalfFloat     => gl::HALF_FLOAT,
+            AttributeType::Float         => gl::FLOAT,
+        }
+    }
+}
+
+// One input of the vertex shader, i.e. `in layout(location=3) vec3 normal_in;`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VertexAttribute {
+    pub location   : u32,
+    pub components : i32,            // 1 to 4
+    pub kind       : AttributeType,
+    pub normalized : bool,           // Whether integers should be mapped to [0, 1] or [-1, 1]
+    pub divisor    : u32,            // 0 to advance every vertex, n to advance every n instances
+}
+
+impl VertexAttribute {
+    pub fn floats(location: u32, components: i32) -> VertexAttribute {
+        VertexAttribute { location, components, kind: AttributeType::Float, normalized: false, divisor: 0 }
+    }
+
+    pub fn per_instance(mut self) -> VertexAttribute {
+        self.divisor = 1;
+        self
+    }
+
+    pub fn size_in_bytes(&self) -> u32 {
+        self.components as u32 * self.kind.size_in_bytes()
+    }
+}
+
+// How the attributes are laid out in a single buffer. Attributes are packed one after the other
+// within each vertex, so a layout with a single attribute describes a tightly packed array.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VertexLayout {
+    pub attributes: Vec<VertexAttribute>,
+}
+
+impl VertexLayout {
+    pub fn new(attributes: &[VertexAttribute]) -> VertexLayout {
+        VertexLayout { attributes: attributes.to_vec() }
+    }
+
+    // The distance in bytes from one vertex to the next
+    pub fn stride(&self) -> i32 {
+        self.attributes.iter().map(|a| a.size_in_bytes() as i32).sum()
+    }
+
+    // The byte offset of each attribute from the start of its vertex
+    pub fn offsets(&self) -> Vec<u32> {
+        self.attributes.iter()
+            .scan(0, |start, a| {
+                let offset = *start;
+                *start += a.size_in_bytes();
+                Some(offset)
+            })
+            .collect()
+    }
+}
+
+pub struct VertexBuffer {
+    id         : u32,
+    size_bytes : isize,
+}
+
+impl VertexBuffer {
+    pub unsafe fn new<T>(data: &[T], usage: gl::types::GLenum) -> VertexBuffer {
+        let mut id = 0;
+        gl::GenBuffers(1, &mut id);
+        gl::BindBuffer(gl::ARRAY_BUFFER, id);
+        gl::BufferData(gl::ARRAY_BUFFER, byte_size_of_array(data), pointer_to_array(data), usage);
+        VertexBuffer { id, size_bytes: byte_size_of_array(data) }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    // Replaces the contents of the buffer, growing it if needed
+    pub unsafe fn update<T>(&mut self, data: &[T], usage: gl::types::GLenum) {
+        gl::BindBuffer(gl::ARRAY_BUFFER, self.id);
+        if byte_size_of_array(data) > self.size_bytes {
+            self.size_bytes = byte_size_of_array(data);
+            gl::BufferData(gl::ARRAY_BUFFER, self.size_bytes, pointer_to_array(data), usage);
+        } else {
+            gl::BufferSubData(gl::ARRAY_BUFFER, 0, byte_size_of_array(data), pointer_to_array(data));
+        }
+    }
+}
+
+impl Drop for VertexBuffer {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteBuffers(1, &self.id) };
+    }
+}
+
+pub struct IndexBuffer {
+    id    : u32,
+    count : i32,
+}
+
+impl IndexBuffer {
+    // Must be created while the vertex array it belongs to is bound
+    pub unsafe fn new(indices: &[u32], usage: gl::types::GLenum) -> IndexBuffer {
+        let mut id = 0;
+        gl::GenBuffers(1, &mut id);
+        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, id);
+        gl::BufferData(gl::ELEMENT_ARRAY_BUFFER, byte_size_of_array(indices), pointer_to_array(indices), usage);
+        IndexBuffer { id, count: indices.len() as i32 }
+    }
+
+    pub fn count(&self) -> i32 {
+        self.count
+    }
+}
+
+impl Drop for IndexBuffer {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteBuffers(1, &self.id) };
+    }
+}
+
+pub struct VertexArray {
+    id             : u32,
+    vertex_buffers : Vec<VertexBuffer>,
+    index_buffer   : Option<IndexBuffer>,
+}
+
+impl VertexArray {
+    pub unsafe fn new() -> VertexArray {
+        let mut id = 0;
+        gl::GenVertexArrays(1, &mut id);
+        VertexArray { id, vertex_buffers: vec![], index_buffer: None }
+    }
+
+    // Uploads one buffer of vertex data, and points the attributes in the layout at it
+    pub unsafe fn add_buffer<T>(&mut self, data: &[T], layout: &VertexLayout) {
+        let buffer = VertexBuffer::new(data, gl::STATIC_DRAW);
+        self.attach_buffer(&buffer, layout);
+        self.vertex_buffers.push(buffer);
+    }
+
+    // Points the attributes in the layout at a buffer which is owned by someone else, such as
+    // per-instance data which changes every frame. The buffer has to outlive its use in drawing.
+    pub unsafe fn attach_buffer(&self, buffer: &VertexBuffer, layout: &VertexLayout) {
+        gl::BindVertexArray(self.id);
+        gl::BindBuffer(gl::ARRAY_BUFFER, buffer.id);
+        for (attribute, &start) in layout.attributes.iter().zip(&layout.offsets()) {
+            gl::VertexAttribPointer(
+                attribute.location,
+                attribute.components,
+                attribute.kind.into(),
+                attribute.normalized as u8,
+                layout.stride(),
+                offset::<u8>(start),
+            );
+            gl::VertexAttribDivisor(attribute.location, attribute.divisor);
+            gl::EnableVertexAttribArray(attribute.location);
+        }
+    }
+
+    pub unsafe fn set_indices(&mut self, indices: &[u32]) {
+        gl::BindVertexArray(self.id);
+        self.index_buffer = Some(IndexBuffer::new(indices, gl::STATIC_DRAW));
+    }
+
+    // Uploads the mesh as a single buffer of interleaved vertices
+    pub unsafe fn from_mesh(mesh: &Mesh) -> VertexArray {
+        let mut vao = VertexArray::new();
+        vao.add_buffer(&mesh.interleaved(), &Vertex::layout());
+        vao.set_indices(&mesh.indices);
+        gl::BindVertexArray(0);
+        vao
+    }
+
+    // Uploads each attribute of the mesh to a buffer of its own. Positions go to location 0,
+    // colours to 1 and normals to 3, to match simple.vert
+    pub unsafe fn from_mesh_split(mesh: &Mesh) -> VertexArray {
+        let mut vao = VertexArray::new();
+        vao.add_buffer(&mesh.vertices, &VertexLayout::new(&[VertexAttribute::floats(0, 3)]));
+        vao.add_buffer(&mesh.colors,   &VertexLayout::new(&[VertexAttribute::floats(1, 4)]));
+        if !mesh.normals.is_empty() {
+            vao.add_buffer(&mesh.normals, &VertexLayout::new(&[VertexAttribute::floats(3, 3)]));
+        }
+        vao.set_indices(&mesh.indices);
+        gl::BindVertexArray(0);
+        vao
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn buffer_count(&self) -> usize {
+        self.vertex_buffers.len() + self.index_buffer.iter().count()
+    }
+
+    // The number of bytes uploaded to the GPU for this vertex array
+    pub fn size_bytes(&self) -> isize {
+        let indices = self.index_buffer.as_ref().map_or(0, |b| b.count as isize * 4);
+        self.vertex_buffers.iter().map(|b| b.size_bytes).sum::<isize>() + indices
+    }
+
+    // How many indices to draw, or -1 if there is no index buffer, to match SceneNode::index_count
+    pub fn index_count(&self) -> i32 {
+        self.index_buffer.as_ref().map_or(-1, |b| b.count())
+    }
+}
+
+impl Drop for VertexArray {
+    fn drop(&mut self) {
+        // The buffers are dropped after this, once nothing refers to them anymore
+        unsafe { gl::DeleteVertexArrays(1, &self.id) };
+    }
+}
+
+// A shared handle to a vertex array, so that any number of scene nodes can draw the same upload.
+// Cloning the handle is cheap, and the vertex array is deleted once the last handle is dropped.
+#[derive(Clone)]
+pub struct GpuMesh {
+    vao: Rc<VertexArray>,
+}
+
+impl GpuMesh {
+    pub fn new(vao: VertexArray) -> GpuMesh {
+        GpuMesh { vao: Rc::new(vao) }
+    }
+
+    pub fn vao_id(&self) -> u32 {
+        self.vao.id()
+    }
+
+    pub fn index_count(&self) -> i32 {
+        self.vao.index_count()
+    }
+
+    pub fn size_bytes(&self) -> isize {
+        self.vao.size_bytes()
+    }
+
+    // How many handles to this mesh exist, including this one
+    pub fn handle_count(&self) -> usize {
+        Rc::strong_count(&self.vao)
+    }
+
+    pub fn vertex_array(&self) -> &VertexArray {
+        &self.vao
+    }
+
+    // A handle which does not keep the mesh alive, for remembering things about it
+    pub fn downgrade(&self) -> Weak<VertexArray> {
+        Rc::downgrade(&self.vao)
+    }
+
+    pub fn is(&self, other: &Weak<VertexArray>) -> bool {
+        Rc::as_ptr(&self.vao) == other.as_ptr()
+    }
+}
+
+// A buffer compute shaders can read and write, declared as `layout(std430, binding=N) buffer`.
+// Nothing in the application runs compute shaders yet, so only the tests make these.
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct StorageBuffer {
+    id         : u32,
+    size_bytes : isize,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl StorageBuffer {
+    pub unsafe fn new<T>(data: &[T], usage: gl::types::GLenum) -> StorageBuffer {
+        let mut id = 0;
+        gl::GenBuffers(1, &mut id);
+        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, id);
+        gl::BufferData(gl::SHADER_STORAGE_BUFFER, byte_size_of_array(data), pointer_to_array(data), usage);
+        StorageBuffer { id, size_bytes: byte_size_of_array(data) }
+    }
+
+    // Makes the buffer what the storage blocks bound to `binding` read and write, see
+    // Shader::bind_storage_block
+    pub unsafe fn bind(&self, binding: u32) {
+        gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, binding, self.id);
+    }
+
+    // Copies the whole buffer back to the CPU, as many T as fit in it. Stalls until the GPU is
+    // done writing it, and needs a memory_barrier(gl::BUFFER_UPDATE_BARRIER_BIT) after a dispatch.
+    pub unsafe fn read<T: Copy + Default>(&self) -> Vec<T> {
+        let mut data = vec![T::default(); self.size_bytes as usize / std::mem::size_of::<T>()];
+        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, self.id);
+        gl::GetBufferSubData(gl::SHADER_STORAGE_BUFFER, 0, byte_size_of_array(&data), data.as_mut_ptr() as *mut _);
+        data
+    }
+}
+
+impl Drop for StorageBuffer {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteBuffers(1, &self.id) };
+    }
+}
+
+// What a compute shader wrote is only guaranteed to be visible to later commands after a barrier
+// for the way they are going to read it, like gl::SHADER_STORAGE_BARRIER_BIT. Combine them with |.
+#[cfg_attr(not(test), allow(dead_code))]
+pub unsafe fn memory_barrier(barriers: gl::types::GLbitfield) {
+    gl::MemoryBarrier(barriers);
+}
+
+// A framebuffer which is not shown anywhere, to render into without a window
+pub struct Framebuffer {
+    id     : u32,
+    colour : u32,                    // Renderbuffers, since they are only ever read back
+    depth  : u32,
+    width  : u32,
+    height : u32,
+}
+
+impl Framebuffer {
+    pub unsafe fn new(width: u32, height: u32) -> Result<Framebuffer, String> {
+        let mut framebuffer = Framebuffer { id: 0, colour: 0, depth: 0, width, height };
+        gl::GenFramebuffers(1, &mut framebuffer.id);
+        gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer.id);
+        for (renderbuffer, format, attachment) in [
+            (&mut framebuffer.colour, gl::RGBA8,             gl::COLOR_ATTACHMENT0),
+            (&mut framebuffer.depth,  gl::DEPTH_COMPONENT24, gl::DEPTH_ATTACHMENT),
+        ] {
+            gl::GenRenderbuffers(1, renderbuffer);
+            gl::BindRenderbuffer(gl::RENDERBUFFER, *renderbuffer);
+            gl::RenderbufferStorage(gl::RENDERBUFFER, format, width as i32, height as i32);
+            gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, attachment, gl::RENDERBUFFER, *renderbuffer);
+        }
+        let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+        gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            // Dropping it deletes what was made so far
+            return Err(format!("The {}x{} framebuffer is incomplete, status 0x{:04X}", width, height, status));
+        }
+        Ok(framebuffer)
+    }
+
+    // Draws and reads go to this framebuffer from now on, and the viewport covers all of it
+    pub unsafe fn bind(&self) {
+        gl::BindFramebuffer(gl::FRAMEBUFFER, self.id);
+        gl::Viewport(0, 0, self.width as i32, self.height as i32);
+    }
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.id);
+            gl::DeleteRenderbuffers(1, &self.colour);
+            gl::DeleteRenderbuffers(1, &self.depth);
+        }
+    }
+}
+
+// Copies the bottom left width x height pixels of the framebuffer bound for reading. OpenGL
+// counts rows from the bottom and images from the top, so it is flipped on the way.
+pub unsafe fn read_pixels(width: u32, height: u32) -> image::RgbaImage {
+    let mut pixels = vec![0u8; width as usize * height as usize * 4];
+    gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+    gl::ReadPixels(0, 0, width as i32, height as i32, gl::RGBA, gl::UNSIGNED_BYTE, pixels.as_mut_ptr() as *mut _);
+    let image = image::RgbaImage::from_raw(width, height, pixels).unwrap();
+    image::imageops::flip_vertical(&image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::{self, HeadlessContext};
+    use crate::shader::{ShaderBuilder, ShaderType};
+
+    #[test]
+    fn interleaved_layout_offsets_and_stride() {
+        let layout = VertexLayout::new(&[
+            VertexAttribute::floats(0, 3),
+            VertexAttribute { location: 1, components: 4, kind: AttributeType::UnsignedByte, normalized: true, divisor: 0 },
+            VertexAttribute::floats(3, 3),
+            VertexAttribute { location: 4, components: 2, kind: AttributeType::HalfFloat, normalized: false, divisor: 1 },
+        ]);
+        assert_eq!(layout.offsets(), vec![0, 12, 16, 28]);
+        assert_eq!(layout.stride(), 32);
+    }
+
+    #[test]
+    fn single_attribute_layout_is_tightly_packed() {
+        let layout = VertexLayout::new(&[VertexAttribute::floats(1, 4)]);
+        assert_eq!(layout.offsets(), vec![0]);
+        assert_eq!(layout.stride(), 16);
+    }
+
+    #[test]
+    fn compute_shaders_write_to_storage_buffers() {
+        let _context = match headless::unless_skipped("compute_shaders_write_to_storage_buffers", unsafe { HeadlessContext::new() }) {
+            Some(context) => context,
+            None => return,
+        };
+        unsafe {
+            let mut program = ShaderBuilder::new()
+                .compile_shader("#version 430 core
+                    layout(local_size_x = 64) in;
+                    layout(std430, binding = 0) buffer Values { uint values[]; };
+                    uniform uint count;
+                    void main() {
+                        uint i = gl_GlobalInvocationID.x;
+                        if (i < count) { values[i] = values[i] * 2u + 1u; }
+                    }", ShaderType::Compute).unwrap()
+                .link().unwrap();
+            let values: Vec<u32> = (0..100).collect();
+            let buffer = StorageBuffer::new(&values, gl::DYNAMIC_COPY);
+            buffer.bind(3);
+            program.bind_storage_block("Values", 3).unwrap();
+            program.set_u32("count", 100).unwrap();
+            program.dispatch_items([100, 1, 1]).unwrap();
+            memory_barrier(gl::BUFFER_UPDATE_BARRIER_BIT);
+            let expected: Vec<u32> = values.iter().map(|v| v * 2 + 1).collect();
+            assert_eq!(buffer.read::<u32>(), expected);
+        }
+    }
+}
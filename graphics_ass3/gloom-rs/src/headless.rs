@@ -0,0 +1,428 @@
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::{c_char, c_void};
+use std::path::Path;
+use std::ptr;
+
+use crate::app::App;
+use crate::capture::Recorder;
+use crate::gpu::{self, Framebuffer};
+
+// Renders without a window, into an offscreen framebuffer, and saves the result as a PNG:
+//
+//     cargo run -- --headless --frames 120 --time 2.0 --size 400x300 --out helicopters.png
+//
+// With --record DIR, every frame is saved to DIR as well, numbered from frame_00000.png, to be
+// made into a video. See capture.rs.
+//
+// The context comes from EGL on Mesa's surfaceless platform, which needs neither a display nor a
+// GPU; Mesa falls back to rendering on the CPU with llvmpipe. That makes it possible to test what
+// is drawn, see the golden image tests at the bottom.
+//
+// Time does not pass by itself here. Every frame is simulated `step` seconds after the previous
+// one, so the same options always give the same image.
+
+pub struct HeadlessOptions {
+    pub scene  : String,
+    pub width  : u32,
+    pub height : u32,
+    pub frames : u32,
+    pub time   : f32,                // The simulated time of the first frame, in seconds
+    pub step   : f32,                // Simulated seconds between frames
+    pub output : String,
+    pub record : Option<String>,     // Where to save every frame, if anywhere
+}
+
+impl Default for HeadlessOptions {
+    fn default() -> HeadlessOptions {
+        HeadlessOptions {
+            scene: "scenes/helicopters.ron".to_string(),
+            width: crate::SCREEN_W,
+            height: crate::SCREEN_H,
+            frames: 1,
+            time: 0.0,
+            step: 1.0 / 60.0,
+            output: "headless.png".to_string(),
+            record: None,
+        }
+    }
+}
+
+impl HeadlessOptions {
+    pub fn parse(args: &[String]) -> Result<HeadlessOptions, String> {
+        let mut options = HeadlessOptions::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| format!("{} needs a value", flag))?;
+            let invalid = || format!("Invalid value for {}: {}", flag, value);
+            match flag.as_str() {
+                "--scene"  => options.scene = value.clone(),
+                "--out"    => options.output = value.clone(),
+                "--record" => options.record = Some(value.clone()),
+                "--frames" => options.frames = value.parse().map_err(|_| invalid())?,
+                "--time"   => options.time = value.parse().map_err(|_| invalid())?,
+                "--step"   => options.step = value.parse().map_err(|_| invalid())?,
+                "--size"   => {
+                    let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+                    options.width = width.parse().map_err(|_| invalid())?;
+                    options.height = height.parse().map_err(|_| invalid())?;
+                },
+                _ => return Err(format!("Unknown option {}, expected --scene, --size, --frames, --time, --step, --out or --record", flag)),
+            }
+        }
+        if options.frames == 0 || options.width == 0 || options.height == 0 {
+            return Err("--frames and --size have to be at least 1".to_string());
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Debug)]
+pub enum HeadlessError {
+    Library(libloading::Error),      // libEGL could not be loaded
+    Egl { call: &'static str, code: i32 },
+    Framebuffer(String),
+    App(String),
+    Image(image::ImageError),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for HeadlessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeadlessError::Library(e) => write!(f, "Could not load libEGL, which headless rendering needs: {}", e),
+            HeadlessError::Egl { call, code } => write!(f, "{} failed with EGL error 0x{:04X}", call, code),
+            HeadlessError::Framebuffer(e) => write!(f, "{}", e),
+            HeadlessError::App(e) => write!(f, "{}", e),
+            HeadlessError::Image(e) => write!(f, "Could not save the image: {}", e),
+            HeadlessError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for HeadlessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HeadlessError::Library(e) => Some(e),
+            HeadlessError::Image(e) => Some(e),
+            HeadlessError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// The parts of EGL we need, loaded at runtime, so that the application itself does not need
+// libEGL to start
+type Display = *mut c_void;
+type Config = *mut c_void;
+type Context = *mut c_void;
+type GetPlatformDisplay = unsafe extern "system" fn(u32, *mut c_void, *const i32) -> Display;
+
+const EGL_PLATFORM_SURFACELESS_MESA: u32 = 0x31DD;
+const EGL_OPENGL_API: u32 = 0x30A2;
+const EGL_NONE: i32 = 0x3038;
+const EGL_RENDERABLE_TYPE: i32 = 0x3040;
+const EGL_OPENGL_BIT: i32 = 0x0008;
+const EGL_SURFACE_TYPE: i32 = 0x3033;
+const EGL_CONTEXT_MAJOR_VERSION: i32 = 0x3098;
+const EGL_CONTEXT_MINOR_VERSION: i32 = 0x30FB;
+const EGL_CONTEXT_OPENGL_PROFILE_MASK: i32 = 0x30FD;
+const EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT: i32 = 0x0001;
+
+struct Egl {
+    _library             : libloading::Library,   // The functions below point into it
+    get_proc_address     : unsafe extern "system" fn(*const c_char) -> *const c_void,
+    get_platform_display : GetPlatformDisplay,
+    initialize           : unsafe extern "system" fn(Display, *mut i32, *mut i32) -> u32,
+    terminate            : unsafe extern "system" fn(Display) -> u32,
+    bind_api             : unsafe extern "system" fn(u32) -> u32,
+    choose_config        : unsafe extern "system" fn(Display, *const i32, *mut Config, i32, *mut i32) -> u32,
+    create_context       : unsafe extern "system" fn(Display, Config, Context, *const i32) -> Context,
+    destroy_context      : unsafe extern "system" fn(Display, Context) -> u32,
+    make_current         : unsafe extern "system" fn(Display, *mut c_void, *mut c_void, Context) -> u32,
+    get_error            : unsafe extern "system" fn() -> i32,
+}
+
+impl Egl {
+    unsafe fn load() -> Result<Egl, HeadlessError> {
+        let library = libloading::Library::new("libEGL.so.1").map_err(HeadlessError::Library)?;
+        macro_rules! symbol {
+            ($name:literal) => { *library.get(concat!($name, "\0").as_bytes()).map_err(HeadlessError::Library)? };
+        }
+        let get_proc_address: unsafe extern "system" fn(*const c_char) -> *const c_void = symbol!("eglGetProcAddress");
+        // An extension function, which is only found through eglGetProcAddress
+        let get_platform_display = get_proc_address(b"eglGetPlatformDisplayEXT\0".as_ptr() as *const c_char);
+        if get_platform_display.is_null() {
+            return Err(HeadlessError::Egl { call: "eglGetProcAddress(eglGetPlatformDisplayEXT)", code: 0 });
+        }
+        Ok(Egl {
+            get_proc_address,
+            get_platform_display: std::mem::transmute::<*const c_void, GetPlatformDisplay>(get_platform_display),
+            initialize: symbol!("eglInitialize"),
+            terminate: symbol!("eglTerminate"),
+            bind_api: symbol!("eglBindAPI"),
+            choose_config: symbol!("eglChooseConfig"),
+            create_context: symbol!("eglCreateContext"),
+            destroy_context: symbol!("eglDestroyContext"),
+            make_current: symbol!("eglMakeCurrent"),
+            get_error: symbol!("eglGetError"),
+            _library: library,
+        })
+    }
+
+    unsafe fn check(&self, call: &'static str, ok: bool) -> Result<(), HeadlessError> {
+        if ok { Ok(()) } else { Err(HeadlessError::Egl { call, code: (self.get_error)() }) }
+    }
+}
+
+// An OpenGL 4.3 core context without any window, current on the thread which made it
+pub struct HeadlessContext {
+    egl     : Egl,
+    display : Display,
+    context : Context,
+}
+
+impl HeadlessContext {
+    pub unsafe fn new() -> Result<HeadlessContext, HeadlessError> {
+        let egl = Egl::load()?;
+        let display = (egl.get_platform_display)(EGL_PLATFORM_SURFACELESS_MESA, ptr::null_mut(), ptr::null());
+        egl.check("eglGetPlatformDisplayEXT", !display.is_null())?;
+        let (mut major, mut minor) = (0, 0);
+        egl.check("eglInitialize", (egl.initialize)(display, &mut major, &mut minor) != 0)?;
+        // From here on, dropping the context cleans up after it
+        let mut headless = HeadlessContext { egl, display, context: ptr::null_mut() };
+        let egl = &headless.egl;
+        egl.check("eglBindAPI", (egl.bind_api)(EGL_OPENGL_API) != 0)?;
+
+        let config_attributes = [EGL_RENDERABLE_TYPE, EGL_OPENGL_BIT, EGL_SURFACE_TYPE, 0, EGL_NONE];
+        let (mut config, mut configs) = (ptr::null_mut(), 0);
+        let chosen = (egl.choose_config)(display, config_attributes.as_ptr(), &mut config, 1, &mut configs);
+        egl.check("eglChooseConfig", chosen != 0 && configs > 0)?;
+
+        let context_attributes = [
+            EGL_CONTEXT_MAJOR_VERSION, 4,
+            EGL_CONTEXT_MINOR_VERSION, 3,
+            EGL_CONTEXT_OPENGL_PROFILE_MASK, EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT,
+            EGL_NONE,
+        ];
+        headless.context = (egl.create_context)(display, config, ptr::null_mut(), context_attributes.as_ptr());
+        egl.check("eglCreateContext", !headless.context.is_null())?;
+        egl.check("eglMakeCurrent", (egl.make_current)(display, ptr::null_mut(), ptr::null_mut(), headless.context) != 0)?;
+
+        gl::load_with(|symbol| {
+            let symbol = CString::new(symbol).unwrap();
+            (egl.get_proc_address)(symbol.as_ptr())
+        });
+        Ok(headless)
+    }
+}
+
+impl Drop for HeadlessContext {
+    fn drop(&mut self) {
+        unsafe {
+            (self.egl.make_current)(self.display, ptr::null_mut(), ptr::null_mut(), ptr::null_mut());
+            if !self.context.is_null() {
+                (self.egl.destroy_context)(self.display, self.context);
+            }
+            (self.egl.terminate)(self.display);
+        }
+    }
+}
+
+// Renders the frames and gives back the last one
+pub fn render(options: &HeadlessOptions) -> Result<image::RgbaImage, HeadlessError> {
+    unsafe {
+        // Declared in this order so that they are dropped in the opposite one, with the context last
+        let _context = HeadlessContext::new()?;
+        let framebuffer = Framebuffer::new(options.width, options.height).map_err(HeadlessError::Framebuffer)?;
+        framebuffer.bind();
+        let mut app = App::new(&options.scene, None).map_err(HeadlessError::App)?;
+        app.resize(options.width, options.height);
+        let mut recorder = match &options.record {
+            Some(dir) => Some(Recorder::new(Path::new(dir), 1.0 / options.step).map_err(HeadlessError::Io)?),
+            None      => None,
+        };
+
+        for frame in 0..options.frames {
+            let elapsed = options.time + frame as f32 * options.step;
+            app.update(elapsed);
+            app.draw(elapsed);
+            if let Some(recorder) = &mut recorder {
+                recorder.capture(options.width, options.height).map_err(HeadlessError::Image)?;
+            }
+        }
+        Ok(gpu::read_pixels(options.width, options.height))
+    }
+}
+
+pub fn run(args: &[String]) {
+    let options = HeadlessOptions::parse(args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let saved = render(&options).and_then(|image| image.save(&options.output).map_err(HeadlessError::Image));
+    match saved {
+        Ok(()) => {
+            println!("Saved frame {} of {} to {}", options.frames, options.scene, options.output);
+            if let Some(dir) = &options.record {
+                println!("Saved all {} frames to {}", options.frames, dir);
+            }
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    }
+}
+
+// How far apart two images of the same size are, for the golden image tests
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageDifference {
+    pub differing : usize,           // Pixels where some channel is off by more than the tolerance
+    pub total     : usize,
+    pub largest   : u8,              // The largest difference of any channel
+}
+
+#[cfg(test)]
+impl ImageDifference {
+    pub fn fraction(&self) -> f64 {
+        self.differing as f64 / self.total.max(1) as f64
+    }
+}
+
+// None if the images are not the same size
+#[cfg(test)]
+pub fn compare(expected: &image::RgbaImage, actual: &image::RgbaImage, tolerance: u8) -> Option<ImageDifference> {
+    if expected.dimensions() != actual.dimensions() {
+        return None;
+    }
+    let mut difference = ImageDifference { differing: 0, total: 0, largest: 0 };
+    for (a, b) in expected.pixels().zip(actual.pixels()) {
+        let largest = a.0.iter().zip(b.0.iter()).map(|(a, b)| a.abs_diff(*b)).max().unwrap_or(0);
+        difference.largest = difference.largest.max(largest);
+        difference.differing += (largest > tolerance) as usize;
+        difference.total += 1;
+    }
+    Some(difference)
+}
+
+// The tests which need OpenGL fail without libEGL. Set SKIP_GPU_TESTS=1 to skip them on purpose
+// where there is none, in which case this gives None.
+#[cfg(test)]
+pub fn unless_skipped<T>(test: &str, result: Result<T, HeadlessError>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(HeadlessError::Library(e)) if std::env::var_os("SKIP_GPU_TESTS").is_some() => {
+            eprintln!("Skipping {}, there is no libEGL: {}", test, e);
+            None
+        },
+        Err(e @ HeadlessError::Library(_)) => panic!("{}. Set SKIP_GPU_TESTS=1 to skip the tests which need OpenGL", e),
+        Err(e) => panic!("{}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        let options = HeadlessOptions::parse(&args("--size 320x240 --frames 10 --time 1.5 --out a.png")).unwrap();
+        assert_eq!((options.width, options.height, options.frames), (320, 240, 10));
+        assert_eq!((options.time, options.output.as_str()), (1.5, "a.png"));
+        assert_eq!((options.scene.as_str(), options.record), ("scenes/helicopters.ron", None));
+        let options = HeadlessOptions::parse(&args("--record frames --step 0.04")).unwrap();
+        assert_eq!((options.record.as_deref(), options.step), (Some("frames"), 0.04));
+        assert!(HeadlessOptions::parse(&args("--size 320")).is_err());
+        assert!(HeadlessOptions::parse(&args("--frames")).is_err());
+        assert!(HeadlessOptions::parse(&args("--frames 0")).is_err());
+        assert!(HeadlessOptions::parse(&args("--fps 60")).is_err());
+    }
+
+    #[test]
+    fn small_differences_are_tolerated() {
+        let expected = image::RgbaImage::from_pixel(4, 4, image::Rgba([100, 100, 100, 255]));
+        let mut actual = expected.clone();
+        actual.put_pixel(0, 0, image::Rgba([103, 100, 100, 255]));
+        actual.put_pixel(1, 0, image::Rgba([100, 140, 100, 255]));
+        let difference = compare(&expected, &actual, 3).unwrap();
+        assert_eq!(difference, ImageDifference { differing: 1, total: 16, largest: 40 });
+        assert_eq!(compare(&expected, &image::RgbaImage::new(4, 3), 3), None);
+    }
+
+    // Golden images are kept in tests/golden, of tests/scenes/cubes.ron which only needs the
+    // meshes in tests/meshes. Set UPDATE_GOLDEN=1 to write them anew, after
+    // checking that a change in how things look is on purpose. Software rendering may differ a
+    // little between Mesa versions, hence the tolerance.
+    const TOLERANCE: u8 = 8;
+    const MAX_DIFFERING: f64 = 0.005;
+
+    fn render_unless_skipped(test: &str, options: &HeadlessOptions) -> Option<image::RgbaImage> {
+        unless_skipped(test, render(options))
+    }
+
+    fn check_golden(name: &str, options: &HeadlessOptions) {
+        let image = match render_unless_skipped(name, options) {
+            Some(image) => image,
+            None => return,
+        };
+        let golden = format!("tests/golden/{}.png", name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::create_dir_all("tests/golden").unwrap();
+            image.save(&golden).unwrap();
+            return;
+        }
+
+        // Keep what was rendered next to the golden image, to look at the two when they differ
+        let actual = format!("target/golden/{}.png", name);
+        std::fs::create_dir_all("target/golden").unwrap();
+        image.save(&actual).unwrap();
+
+        let expected = image::open(&golden)
+            .unwrap_or_else(|e| panic!("Could not open {}: {}. Run with UPDATE_GOLDEN=1 to make it", golden, e))
+            .to_rgba8();
+        match compare(&expected, &image, TOLERANCE) {
+            None => panic!("{} is {:?}, but {} is {:?}", golden, expected.dimensions(), actual, image.dimensions()),
+            Some(difference) => assert!(
+                difference.fraction() <= MAX_DIFFERING,
+                "{} of {} pixels in {} differ from {} by more than {}, by up to {}",
+                difference.differing, difference.total, actual, golden, TOLERANCE, difference.largest,
+            ),
+        }
+    }
+
+    fn cubes() -> HeadlessOptions {
+        HeadlessOptions { scene: "tests/scenes/cubes.ron".to_string(), width: 200, height: 150, ..HeadlessOptions::default() }
+    }
+
+    #[test]
+    fn cubes_at_the_start() {
+        check_golden("cubes_start", &cubes());
+    }
+
+    #[test]
+    fn cubes_after_two_seconds() {
+        check_golden("cubes_2s", &HeadlessOptions { frames: 3, time: 2.0, ..cubes() });
+    }
+
+    #[test]
+    fn every_frame_is_recorded() {
+        let dir = "target/recording_test";
+        let _ = std::fs::remove_dir_all(dir);
+        let options = HeadlessOptions { frames: 3, time: 1.0, step: 0.5, record: Some(dir.to_string()), ..cubes() };
+        let last = match render_unless_skipped("every_frame_is_recorded", &options) {
+            Some(image) => image,
+            None => return,
+        };
+        let mut frames: Vec<_> = std::fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        frames.sort();
+        assert_eq!(frames, vec!["frame_00000.png", "frame_00001.png", "frame_00002.png"]);
+        let recorded = image::open(format!("{}/frame_00002.png", dir)).unwrap().to_rgba8();
+        assert_eq!(compare(&last, &recorded, 0).unwrap().differing, 0);
+    }
+}
@@ -1,14 +1,32 @@
 extern crate nalgebra_glm as glm;
-use std::{ mem, ptr, os::raw::c_void };
+use std::{ mem, os::raw::c_void };
 use std::thread;
+use std::path::Path;
 use std::sync::{Mutex, Arc, RwLock};
 
 mod shader;
+mod shader_log;
+mod shader_reflection;
 mod util;
 mod mesh;
 mod scene_graph;
-use scene_graph::SceneNode;
+mod rotation;
 mod toolbox;
+mod scene_file;
+mod gpu;
+mod assets;
+mod bench;
+mod renderer;
+mod uniform_buffer;
+mod program_cache;
+mod debug_output;
+mod app;
+mod headless;
+mod capture;
+mod camera;
+mod camera_controller;
+#[cfg(test)]
+mod shader_validation;
 
 use glutin::event::{Event, WindowEvent, DeviceEvent, KeyboardInput, ElementState::{Pressed, Released}, VirtualKeyCode::{self, *}};
 use glutin::event_loop::ControlFlow;
@@ -28,6 +46,7 @@ fn pointer_to_array<T>(val: &[T]) -> *const c_void {
 }
 
 // Get the size of the given type in bytes
+#[allow(dead_code)] // VertexLayout works the sizes out itself since set_up_VAO went away
 fn size_of<T>() -> i32 {
     mem::size_of::<T>() as i32
 }
@@ -42,117 +61,19 @@ fn offset<T>(n: u32) -> *const c_void {
 
 
 
-// == // Modify and complete the function below for the first task
-unsafe fn set_up_VAO(vertices: &Vec<f32>, colour: &Vec<f32>, indices: &Vec<u32>, normals: &Vec<f32>) -> u32 {
-    let mut array_ID: u32 = 0;
-    let number_of_VAOs = 1;
-
-    /* Vertex Array Object */
-    gl::GenVertexArrays(number_of_VAOs, &mut array_ID as *mut u32);
-    gl::BindVertexArray(array_ID);
-
-    /* Vertex Buffer Object for vertices */
-    let mut vertex_buffer_ID: u32 = 0;
-    let number_of_VBOs = 1;
-
-    gl::GenBuffers(number_of_VBOs, &mut vertex_buffer_ID as *mut u32);
-    gl::BindBuffer(gl::ARRAY_BUFFER, vertex_buffer_ID);
-
-    gl::BufferData(gl::ARRAY_BUFFER, byte_size_of_array(vertices), pointer_to_array(vertices), gl::STATIC_DRAW);
-
-    let vertex_attrib_ptr_idx = 0;
-    let components_per_vertex = 3;
-    let stride: i32 = 0;         //Set to 0 because input only contains vertices, and thus OpenGL can figure it out by itself
-    let first_data = ptr::null();
-    gl::VertexAttribPointer(vertex_attrib_ptr_idx, components_per_vertex, 
-                            gl::FLOAT, gl::FALSE, stride, first_data);
-
-    gl::EnableVertexAttribArray(vertex_attrib_ptr_idx);
-
-    /* Vertex Buffer Object for colours */
-
-    let mut colour_buffer_ID: u32 = 0;
-    let number_of_CVBOs = 1;
-
-    gl::GenBuffers(number_of_CVBOs, &mut colour_buffer_ID as *mut u32);
-    gl::BindBuffer(gl::ARRAY_BUFFER, colour_buffer_ID);
-
-    gl::BufferData(gl::ARRAY_BUFFER, byte_size_of_array(colour), pointer_to_array(colour), gl::STATIC_DRAW);
-
-    let colour_attrib_ptr_idx = 1;
-    let components_per_colour = 4;
-
-    gl::VertexAttribPointer(colour_attrib_ptr_idx, components_per_colour,
-                            gl::FLOAT, gl::FALSE, stride, first_data);
-    
-    gl::EnableVertexAttribArray(colour_attrib_ptr_idx);
-
-    /* Vertex Buffer Object for normals */
-
-    let mut normal_buffer_ID: u32 = 0;
-    let number_of_NVBOs = 1;
-
-    gl::GenBuffers(number_of_NVBOs, &mut normal_buffer_ID as *mut u32);
-    gl::BindBuffer(gl::ARRAY_BUFFER, normal_buffer_ID);
-
-    gl::BufferData(gl::ARRAY_BUFFER, byte_size_of_array(normals), pointer_to_array(normals), gl::STATIC_DRAW);
-
-    let normal_attrib_ptr_idx = 3;
-    let components_per_normal = 3;
-
-    gl::VertexAttribPointer(normal_attrib_ptr_idx, components_per_normal,
-                            gl::FLOAT, gl::FALSE, stride, first_data);
-    
-    gl::EnableVertexAttribArray(normal_attrib_ptr_idx);
-
-    /* Vertex Buffer Object for indices */
-    let mut idx_buffer_ID: u32 = 0;
-    let number_of_IVBOs = 1;
-
-    gl::GenBuffers(number_of_IVBOs, &mut idx_buffer_ID as *mut u32);
-    gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, idx_buffer_ID);
-
-    gl::BufferData(gl::ELEMENT_ARRAY_BUFFER, byte_size_of_array(indices), pointer_to_array(indices), gl::STATIC_DRAW);
-
-    return array_ID;
-}
-
-unsafe fn draw_scene(node: &scene_graph::SceneNode, view_projection_matrix: &glm::Mat4){
-    if node.index_count >= 0 {
-        gl::UniformMatrix4fv(2, 1, 0, node.current_transformation_matrix.as_ptr());
-        gl::UniformMatrix4fv(4, 1, 0, view_projection_matrix.as_ptr());
-        gl::BindVertexArray(node.vao_id);
-        gl::DrawElements(gl::TRIANGLES, node.index_count, gl::UNSIGNED_INT, ptr::null());
-    }
-    
-    for &child in &node.children{
-        draw_scene(&*child, view_projection_matrix);
+fn main() {
+    // Measure vertex upload speed instead of running the application
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--bench-upload") {
+        bench::run(args.get(2).map_or("scenes/helicopters.ron", String::as_str));
+        return;
     }
-}
-
-unsafe fn update_node_transformations(node: &mut scene_graph::SceneNode, transformation_so_far: &glm::Mat4){
-    //Move to reference point
-    let mut trans = glm::translation(&glm::vec3(-node.reference_point.x, -node.reference_point.y, -node.reference_point.z));
-    //Apply rotations
-    trans = glm::rotation(node.rotation.z, &glm::vec3(0.0, 0.0, 1.0))*trans;
-    trans = glm::rotation(node.rotation.y, &glm::vec3(0.0, 1.0, 0.0))*trans;
-    trans = glm::rotation(node.rotation.x, &glm::vec3(1.0, 0.0, 0.0))*trans;
-    //Move back from reference point
-    trans = glm::translation(&glm::vec3(node.reference_point.x, node.reference_point.y, node.reference_point.z))*trans;
-    //Apply parent transformation
-    trans = (*transformation_so_far)*trans;
-    //Apply translation
-    trans = glm::translation(&glm::vec3(node.position.x, node.position.y, node.position.z))*trans;
-
-    //Update the current transformation 
-    node.current_transformation_matrix = trans;
-
-    for &child in &node.children{
-        update_node_transformations(&mut *child, &node.current_transformation_matrix);
+    // Render a few frames without a window, and save the last one
+    if args.get(1).map(String::as_str) == Some("--headless") {
+        headless::run(&args[2..]);
+        return;
     }
-}
 
-fn main() {
     // Set up the necessary objects to deal with windows and event handling
     let el = glutin::event_loop::EventLoop::new();
     let wb = glutin::window::WindowBuilder::new()
@@ -160,17 +81,23 @@ fn main() {
         .with_resizable(false)
         .with_inner_size(glutin::dpi::LogicalSize::new(SCREEN_W, SCREEN_H));
     let cb = glutin::ContextBuilder::new()
-        .with_vsync(true);
+        .with_vsync(true)
+        .with_gl_debug_flag(cfg!(debug_assertions));
     let windowed_context = cb.build_windowed(wb, &el).unwrap();
-    // Uncomment these if you want to use the mouse for controls, but want it to be confined to the screen and/or invisible.
-    // windowed_context.window().set_cursor_grab(true).expect("failed to grab cursor");
-    // windowed_context.window().set_cursor_visible(false);
+    // Press G to confine the cursor to the window and hide it, for looking around with the mouse
+    // without it wandering off. The mouse only moves the camera while it is grabbed like this.
+    // Press G again to get it back.
 
     // Set up a shared vector for keeping track of currently pressed keys
     let arc_pressed_keys = Arc::new(Mutex::new(Vec::<VirtualKeyCode>::with_capacity(10)));
     // Make a reference of this vector to send to the render thread
     let pressed_keys = Arc::clone(&arc_pressed_keys);
 
+    // Keys which went down since the render thread last looked, for things which should happen
+    // once per press rather than for as long as the key is held
+    let arc_tapped_keys = Arc::new(Mutex::new(Vec::<VirtualKeyCode>::new()));
+    let tapped_keys = Arc::clone(&arc_tapped_keys);
+
     // Set up shared tuple for tracking mouse movement between frames
     let arc_mouse_delta = Arc::new(Mutex::new((0f32, 0f32)));
     // Make a reference of this tuple to send to the render thread
@@ -186,164 +113,124 @@ fn main() {
             c
         };
 
-        // Set up openGL
+        // Print some diagnostics
         unsafe {
-            gl::Enable(gl::DEPTH_TEST);
-            gl::DepthFunc(gl::LESS);
-            gl::Enable(gl::CULL_FACE);
-            gl::Disable(gl::MULTISAMPLE);
-            gl::Enable(gl::BLEND);
-            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
-            gl::Enable(gl::DEBUG_OUTPUT_SYNCHRONOUS);
-            gl::DebugMessageCallback(Some(util::debug_callback), ptr::null());
-
-            // Print some diagnostics
             println!("{}: {}", util::get_gl_string(gl::VENDOR), util::get_gl_string(gl::RENDERER));
             println!("OpenGL\t: {}", util::get_gl_string(gl::VERSION));
             println!("GLSL\t: {}", util::get_gl_string(gl::SHADING_LANGUAGE_VERSION));
         }
 
-        // == // Set up your VAO here
-        let (mut terrain_vao, mut body_vao, mut door_vao, mut main_rotor_vao, mut tail_rotor_vao);
-        
-        let terrain_path = "resources/lunarsurface.obj";
-        let surface = mesh::Terrain::load(&terrain_path);
-
-        let helicopter_path = "resources/helicopter.obj";
-        let helicopter = mesh::Helicopter::load(&helicopter_path);
-
-        unsafe {terrain_vao = set_up_VAO(&surface.vertices, &surface.colors, &surface.indices, &surface.normals);}
-
-        let mut root = SceneNode::new();
-        let mut terrain_node = SceneNode::from_vao(terrain_vao, surface.index_count);
+        // Driver messages are printed as they come. Add .panic_on_error(true) and check the guard
+        // every frame to stop at the first error, or .sink(debug_output::Sink::file("gl.log")?) to
+        // keep them
+        let _debug_output = unsafe {
+            debug_output::DebugOutput::new()
+                .min_severity(debug_output::Severity::Low)
+                .sink(debug_output::Sink::Stdout)
+                .install()
+        };
 
-        root.add_child(&terrain_node);
-
-        for i in 0..=4{
-            unsafe {
-                body_vao = set_up_VAO(&helicopter.body.vertices, &helicopter.body.colors, &helicopter.body.indices, &helicopter.body.normals);
-                door_vao = set_up_VAO(&helicopter.door.vertices, &helicopter.door.colors, &helicopter.door.indices, &helicopter.door.normals);
-                main_rotor_vao = set_up_VAO(&helicopter.main_rotor.vertices, &helicopter.main_rotor.colors, &helicopter.main_rotor.indices, &helicopter.main_rotor.normals);
-                tail_rotor_vao = set_up_VAO(&helicopter.tail_rotor.vertices, &helicopter.tail_rotor.colors, &helicopter.tail_rotor.indices, &helicopter.tail_rotor.normals);
-            
-                (*root.children[0]).add_child(&SceneNode::from_vao(body_vao, helicopter.body.index_count));
-                (*(*root.children[0]).children[i]).add_child(&SceneNode::from_vao(door_vao, helicopter.door.index_count));
-                (*(*root.children[0]).children[i]).add_child(&SceneNode::from_vao(main_rotor_vao, helicopter.main_rotor.index_count));
-                (*(*root.children[0]).children[i]).add_child(&SceneNode::from_vao(tail_rotor_vao, helicopter.tail_rotor.index_count));
-                (*(*(*root.children[0]).children[i]).children[1]).reference_point = glm::vec3(0.0, 0.0, 0.0);
-                (*(*(*root.children[0]).children[i]).children[2]).reference_point = glm::vec3(0.35, 2.3, 10.4);
-            }
-        }
-        
-
-        // Basic usage of shader helper:
-        // The example code below returns a shader object, which contains the field `.program_id`.
-        // The snippet is not enough to do the assignment, and will need to be modified (outside of
-        // just using the correct path), but it only needs to be called once
-        //
-        //     shader::ShaderBuilder::new()
-        //        .attach_file("./path/to/shader.file")
-        //        .link();
-        let program;
-        unsafe {
-            program = shader::ShaderBuilder::new()
-            .attach_file("shaders/simple.vert")
-            .attach_file("shaders/simple.frag")
-            .link();
-            program.activate();
-        }
-        
+        // Linked programs are kept in shader_cache/, and only built from source when it does not
+        // have them for these sources and this driver.
+        let program_cache = unsafe { program_cache::ProgramCache::new("shader_cache") };
+        let mut app = unsafe { app::App::new("scenes/helicopters.ron", Some(&program_cache)) }
+            .unwrap_or_else(|e| panic!("{}", e));
+        println!("{}", app.meshes());
+        app.watch_shaders(true);
 
         // Used to demonstrate keyboard handling -- feel free to remove
         let mut _arbitrary_number = 0.0;
 
-        let first_frame_time = std::time::Instant::now();
-        let mut last_frame_time = first_frame_time;
+        // F12 saves a screenshot to report/images, F10 starts and stops recording every frame to
+        // recordings/. While recording, time moves by a fixed step per frame instead of with the
+        // clock, so the video plays at the right speed no matter how slowly the frames were drawn.
+        let mut take_screenshot = false;
+        let mut cursor_grabbed = false;
+        let mut recorder: Option<capture::Recorder> = None;
+
+        // Time is added up frame by frame rather than read off the clock, so that it carries on
+        // where it was when a recording stops
+        let mut elapsed = 0.0;
+        let mut last_frame_time = std::time::Instant::now();
         // The main rendering loop
         loop {
             let now = std::time::Instant::now();
-            let elapsed = now.duration_since(first_frame_time).as_secs_f32();
-            let delta_time = now.duration_since(last_frame_time).as_secs_f32();
+            let delta_time = match &recorder {
+                Some(recorder) => recorder.step(),
+                None           => now.duration_since(last_frame_time).as_secs_f32(),
+            };
+            elapsed += delta_time;
             last_frame_time = now;
 
-            // Handle keyboard input
-            if let Ok(keys) = pressed_keys.lock() {
-                for key in keys.iter() {
+            if let Ok(mut tapped) = tapped_keys.lock() {
+                for key in tapped.drain(..) {
                     match key {
-                        VirtualKeyCode::Space => {
-                            unsafe {(*root.children[0]).position[1] -= delta_time*40.0;}
-                        },
-                        VirtualKeyCode::A => {
-                            unsafe {(*root.children[0]).position[0] += delta_time*40.0;}
-                        },
-                        VirtualKeyCode::LShift => {
-                            unsafe {(*root.children[0]).position[1] += delta_time*40.0;}
-                        },
-                        VirtualKeyCode::D => {
-                            unsafe {(*root.children[0]).position[0] -= delta_time*40.0;}
-                        },
-                        VirtualKeyCode::S => {
-                            unsafe {(*root.children[0]).position[2] -= delta_time*40.0;}
-                        },
-                        VirtualKeyCode::W => {
-                            unsafe {(*root.children[0]).position[2] += delta_time*40.0;}
-                        },
-                        VirtualKeyCode::Up => {
-                            unsafe {(*root.children[0]).rotation[0] -= delta_time*0.5;}
-                        },
-                        VirtualKeyCode::Down => {
-                            unsafe {(*root.children[0]).rotation[0] += delta_time*0.5;}
-                        },
-                        VirtualKeyCode::Left => {
-                            unsafe {(*root.children[0]).rotation[1] -= delta_time*0.5;}
+                        F12 => take_screenshot = true,
+                        G => {
+                            cursor_grabbed = !cursor_grabbed;
+                            let window = context.window();
+                            if let Err(e) = window.set_cursor_grab(cursor_grabbed) {
+                                eprintln!("Could not grab the cursor: {}", e);
+                                cursor_grabbed = false;
+                            }
+                            window.set_cursor_visible(!cursor_grabbed);
                         },
-                        VirtualKeyCode::Right => {
-                            unsafe {(*root.children[0]).rotation[1] += delta_time*0.5;}
+                        F10 => match recorder.take() {
+                            Some(stopped) => println!("Recorded {} frames to {}", stopped.frames(), stopped.dir().display()),
+                            None => {
+                                let dir = capture::next_free_path(Path::new("recordings"), "recording", "");
+                                match capture::Recorder::new(&dir, 60.0) {
+                                    Ok(started) => {
+                                        println!("Recording to {}, press F10 to stop", dir.display());
+                                        recorder = Some(started);
+                                    },
+                                    Err(e) => eprintln!("Could not start recording to {}: {}", dir.display(), e),
+                                }
+                            },
                         },
-                        
-
-
-                        _ => { }
+                        _ => app.key_pressed(key),
                     }
                 }
             }
-            // Handle mouse movement. delta contains the x and y movement of the mouse since last frame in pixels
-            if let Ok(mut delta) = mouse_delta.lock() {
-
-
 
+            // Handle keyboard input
+            if let Ok(keys) = pressed_keys.lock() {
+                app.handle_keys(&keys, delta_time);
+            }
+            // Handle mouse movement. delta contains the x and y movement of the mouse since last frame in pixels.
+            // The mouse moves even while the window is in the background, so it only steers the
+            // camera while the cursor is grabbed, and is thrown away otherwise.
+            if let Ok(mut delta) = mouse_delta.lock() {
+                if cursor_grabbed {
+                    app.handle_mouse(*delta);
+                }
                 *delta = (0.0, 0.0);
             }
 
-            unsafe {
-                gl::ClearColor(0.76862745, 0.71372549, 0.94901961, 1.0); // moon raker, full opacity
-                gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
-
-                let offset = 0.8;
+            // The camera takes its aspect ratio from the size of the window
+            let size = context.window().inner_size();
+            app.resize(size.width, size.height);
 
-                for i in 0..=4{
-                    // Issue the necessary commands to draw your scene here
-                    //Make rotors rotate
-                    (*(*(*root.children[0]).children[i]).children[1]).rotation.y = 1.6*elapsed;
-                    (*(*(*root.children[0]).children[i]).children[2]).rotation.x = 1.6*elapsed;
-
-                    //Get animation for helicopter:
-                    let heading: toolbox::Heading = toolbox::simple_heading_animation(elapsed + (i as f32)*offset);
-
-                    (*(*root.children[0]).children[i]).position.x = heading.x;
-                    (*(*root.children[0]).children[i]).position.z = heading.z;
+            unsafe {
+                app.reload_shaders();
+                app.update(elapsed);
+                app.draw(elapsed);
+            }
 
-                    (*(*root.children[0]).children[i]).rotation.y = heading.yaw;
-                    (*(*root.children[0]).children[i]).rotation.x = heading.pitch;
-                    (*(*root.children[0]).children[i]).rotation.z = heading.roll;
+            // Read back what was just drawn, before it is swapped away
+            if take_screenshot {
+                take_screenshot = false;
+                let path = capture::next_free_path(Path::new("report/images"), "screenshot", "png");
+                match unsafe { capture::screenshot(size.width, size.height, &path) } {
+                    Ok(()) => println!("Saved {}", path.display()),
+                    Err(e) => eprintln!("Could not save {}: {}", path.display(), e),
+                }
+            }
+            if let Some(recording) = &mut recorder {
+                if let Err(e) = unsafe { recording.capture(size.width, size.height) } {
+                    eprintln!("Stopped recording, could not save a frame: {}", e);
+                    recorder = None;
                 }
-
-                //Update transformations
-                update_node_transformations(&mut root as &mut scene_graph::SceneNode, &glm::identity());
-
-                let perspective_mat: glm::Mat4 = glm::perspective(1.333, 1.0, 1.0, 1000.0);
-
-                draw_scene(&root, &perspective_mat);
             }
 
             context.swap_buffers().unwrap();
@@ -392,6 +279,9 @@ fn main() {
                         Pressed => {
                             if !keys.contains(&keycode) {
                                 keys.push(keycode);
+                                if let Ok(mut tapped) = arc_tapped_keys.lock() {
+                                    tapped.push(keycode);
+                                }
                             }
                         }
                     }
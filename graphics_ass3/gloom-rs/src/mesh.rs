@@ -1,4 +1,4 @@
-use tobj;
+use crate::gpu::{VertexAttribute, VertexLayout};
 
 // internal helper
 fn generate_color_vec(color: [f32; 4], num: usize) -> Vec<f32> {
@@ -10,108 +10,106 @@ pub struct Mesh {
     pub normals: Vec<f32>,
     pub colors: Vec<f32>,
     pub indices: Vec<u32>,
-    pub index_count: i32,
+}
+
+// All the attributes of one vertex next to each other, for uploading the mesh as a single buffer
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Vertex {
+    pub position : [f32; 3],
+    pub colour   : [f32; 4],
+    pub normal   : [f32; 3],
+}
+
+impl Vertex {
+    // The locations match the inputs of simple.vert
+    pub fn layout() -> VertexLayout {
+        VertexLayout::new(&[
+            VertexAttribute::floats(0, 3),
+            VertexAttribute::floats(1, 4),
+            VertexAttribute::floats(3, 3),
+        ])
+    }
 }
 
 impl Mesh {
     pub fn from(mesh: tobj::Mesh, color: [f32; 4]) -> Self {
         let num_verts = mesh.positions.len() / 3;
-        let index_count = mesh.indices.len() as i32;
         Mesh {
             vertices: mesh.positions,
             normals: mesh.normals,
             indices: mesh.indices,
             colors: generate_color_vec(color, num_verts),
-            index_count,
         }
     }
-}
-
-pub struct Terrain;
-impl Terrain {
-    pub fn load(path: &str) -> Mesh {
-        println!("Loading terrain model...");
-        let before = std::time::Instant::now();
-        let (models, _materials)
-            = tobj::load_obj(path,
-                &tobj::LoadOptions{
-                    triangulate: true,
-                    single_index: true,
-                    ..Default::default()
-                }
-            ).expect("Failed to load terrain model");
-        let after = std::time::Instant::now();
-        println!("Done in {:.3}ms.", after.duration_since(before).as_micros() as f32 / 1e3);
-
-        if models.len() > 1 || models.len() == 0 {
-            panic!("Please use a model with a single mesh!")
-            // You could try merging the vertices and indices
-            // of the separate meshes into a single mesh.
-            // I'll leave that as an optional exercise. ;)
-        }
 
-        let terrain = models[0].to_owned();
-        println!("Loaded {} with {} points and {} triangles.",
-            terrain.name,
-            terrain.mesh.positions.len() /3,
-            terrain.mesh.indices.len() / 3,
-        );
+    pub fn vertex_count(&self) -> usize {
+        self.vertices.len() / 3
+    }
 
-        Mesh::from(terrain.mesh, [1.0, 1.0, 1.0, 1.0])
+    // Packs the separate attribute arrays into one array of vertices. Meshes without normals get
+    // zero normals, so every mesh fits the same layout.
+    pub fn interleaved(&self) -> Vec<Vertex> {
+        (0..self.vertex_count())
+            .map(|i| {
+                let mut vertex = Vertex::default();
+                vertex.position.copy_from_slice(&self.vertices[3*i..3*i + 3]);
+                vertex.colour.copy_from_slice(&self.colors[4*i..4*i + 4]);
+                if let Some(normal) = self.normals.get(3*i..3*i + 3) {
+                    vertex.normal.copy_from_slice(normal);
+                }
+                vertex
+            })
+            .collect()
     }
 }
 
-use std::ops::Index;
-pub struct Helicopter {
-    pub body       : Mesh,
-    pub door       : Mesh,
-    pub main_rotor : Mesh,
-    pub tail_rotor : Mesh,
-}
+// Load a single object from an OBJ file. If no object name is given, the file must contain exactly one
+pub fn load_object(path: &str, object: Option<&str>, color: [f32; 4]) -> Result<Mesh, String> {
+    let (models, _materials)
+        = tobj::load_obj(path,
+            &tobj::LoadOptions{
+                triangulate: true,
+                single_index: true,
+                ..Default::default()
+            }
+        ).map_err(|e| format!("Failed to load {}: {}", path, e))?;
 
-// You can use square brackets to access the components of the helicopter, if you want to use loops!
-impl Index<usize> for Helicopter {
-    type Output = Mesh;
-    fn index<'a>(&'a self, i: usize) -> &'a Mesh {
-        match i {
-            0 => &self.body,
-            1 => &self.main_rotor,
-            2 => &self.tail_rotor,
-            3 => &self.door,
-            _ => panic!("Invalid index, try [0,3]"),
-        }
-    }
+    let model = match object {
+        Some(name) => models.into_iter().find(|m| m.name == name)
+            .ok_or_else(|| format!("{} has no object named {}", path, name))?,
+        None if models.len() == 1 => models.into_iter().next().unwrap(),
+        None => return Err(format!("{} contains {} objects, please name one", path, models.len())),
+    };
+    Ok(Mesh::from(model.mesh, color))
 }
 
-impl Helicopter {
-    pub fn load(path: &str) -> Self {
-        println!("Loading helicopter model...");
-        let before = std::time::Instant::now();
-        let (models, _materials)
-            = tobj::load_obj(path,
-                &tobj::LoadOptions{
-                    triangulate: true,
-                    single_index: true,
-                    ..Default::default()
-                }
-            ).expect("Failed to load helicopter model");
-        let after = std::time::Instant::now();
-        println!("Done in {:.3}ms!", after.duration_since(before).as_micros() as f32 / 1e3);
-
-        for model in &models {
-            println!("Loaded {} with {} points and {} triangles.", model.name, model.mesh.positions.len() / 3, model.mesh.indices.len() / 3);
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
 
-        let body_model = models.iter().find(|m| m.name == "Body_body").expect("Incorrect model file!").to_owned();
-        let door_model = models.iter().find(|m| m.name == "Door_door").expect("Incorrect model file!").to_owned();
-        let main_rotor_model = models.iter().find(|m| m.name == "Main_Rotor_main_rotor").expect("Incorrect model file!").to_owned();
-        let tail_rotor_model = models.iter().find(|m| m.name == "Tail_Rotor_tail_rotor").expect("Incorrect model file!").to_owned();
+    #[test]
+    fn vertex_layout_matches_the_struct() {
+        let layout = Vertex::layout();
+        assert_eq!(layout.stride() as usize, mem::size_of::<Vertex>());
+        assert_eq!(layout.offsets(), vec![
+            mem::offset_of!(Vertex, position) as u32,
+            mem::offset_of!(Vertex, colour) as u32,
+            mem::offset_of!(Vertex, normal) as u32,
+        ]);
+    }
 
-        Helicopter {
-            body:       Mesh::from(body_model.mesh,         [0.3, 0.3, 0.3, 1.0]),
-            door:       Mesh::from(door_model.mesh,         [0.1, 0.1, 0.3, 1.0]),
-            main_rotor: Mesh::from(main_rotor_model.mesh,   [0.3, 0.1, 0.1, 1.0]),
-            tail_rotor: Mesh::from(tail_rotor_model.mesh,   [0.1, 0.3, 0.1, 1.0]),
-        }
+    #[test]
+    fn interleaving_keeps_every_attribute() {
+        let mesh = Mesh {
+            vertices    : vec![0.0, 1.0, 2.0,  3.0, 4.0, 5.0],
+            normals     : vec![0.0, 0.0, 1.0,  0.0, 1.0, 0.0],
+            colors      : generate_color_vec([0.1, 0.2, 0.3, 1.0], 2),
+            indices     : vec![0, 1, 0],
+        };
+        let vertices = mesh.interleaved();
+        assert_eq!(vertices.len(), 2);
+        assert_eq!(vertices[1], Vertex { position: [3.0, 4.0, 5.0], colour: [0.1, 0.2, 0.3, 1.0], normal: [0.0, 1.0, 0.0] });
     }
 }
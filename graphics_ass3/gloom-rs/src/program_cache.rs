@@ -0,0 +1,200 @@
+use std::path::PathBuf;
+
+use crate::shader::ShaderType;
+use crate::util;
+
+// Keeps linked programs on disk with glGetProgramBinary, so that the next start can skip compiling
+// and linking them. A binary is only good for the driver which made it, so the driver goes into
+// the key along with the sources. Drivers are still free to reject a binary, say after an update
+// which did not change the version string, and then the program is just built from source again.
+//
+// Every entry is a file named after its key, holding the binary format as a little endian u32
+// followed by the binary itself. Nothing is ever removed, delete the directory to clear it.
+
+#[derive(Clone, Debug)]
+pub struct ProgramCache {
+    dir    : PathBuf,
+    driver : String,                 // Vendor, renderer and version, as the driver reports them
+}
+
+// What goes into the key for one shader of a program
+pub struct CachedSource<'a> {
+    pub stage  : ShaderType,
+    pub source : &'a str,            // After preprocessing
+}
+
+impl ProgramCache {
+    // Needs a current context, to ask it which driver the binaries are for
+    pub unsafe fn new(dir: &str) -> ProgramCache {
+        let driver = [gl::VENDOR, gl::RENDERER, gl::VERSION]
+            .iter()
+            .map(|&name| util::get_gl_string(name))
+            .collect::<Vec<_>>()
+            .join("\n");
+        ProgramCache { dir: PathBuf::from(dir), driver }
+    }
+
+    pub fn key(&self, sources: &[CachedSource]) -> u64 {
+        cache_key(&self.driver, sources)
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.bin", key))
+    }
+
+    // Tries to make a linked program out of the cached binary. Returns false if there is none, or
+    // if the driver did not take it, in which case the program is left unlinked.
+    pub unsafe fn load(&self, key: u64, program_id: u32) -> bool {
+        let bytes = match std::fs::read(self.path(key)) {
+            Ok(bytes) if bytes.len() > 4 => bytes,
+            _ => return false,
+        };
+        let format = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let binary = &bytes[4..];
+        gl::ProgramBinary(program_id, format, binary.as_ptr() as *const _, binary.len() as i32);
+
+        let mut success = i32::from(gl::FALSE);
+        gl::GetProgramiv(program_id, gl::LINK_STATUS, &mut success);
+        success == i32::from(gl::TRUE)
+    }
+
+    // Saves the binary of a linked program. The program should have been linked with
+    // PROGRAM_BINARY_RETRIEVABLE_HINT set. The cache is only an optimization, so failing to write
+    // it is reported and otherwise ignored.
+    pub unsafe fn store(&self, key: u64, program_id: u32) {
+        let mut length = 0;
+        gl::GetProgramiv(program_id, gl::PROGRAM_BINARY_LENGTH, &mut length);
+        if length <= 0 {
+            return; // The driver does not support any binary formats
+        }
+        let mut binary = vec![0u8; length as usize];
+        let (mut written, mut format) = (0, 0);
+        gl::GetProgramBinary(program_id, length, &mut written, &mut format, binary.as_mut_ptr() as *mut _);
+        binary.truncate(written.max(0) as usize);
+
+        let mut bytes = format.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&binary);
+        let path = self.path(key);
+        let saved = std::fs::create_dir_all(&self.dir).and_then(|_| std::fs::write(&path, bytes));
+        if let Err(e) = saved {
+            eprintln!("Could not save the program binary to {}: {}", path.display(), e);
+        }
+    }
+}
+
+// 64 bit FNV-1a. Unlike the hasher in std, it gives the same hash in every build, which it has
+// to for the keys to still match the next time the program runs.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    // With the length first, so that ("ab", "c") and ("a", "bc") hash differently
+    fn write_str(&mut self, s: &str) {
+        self.write(&(s.len() as u64).to_le_bytes());
+        self.write(s.as_bytes());
+    }
+}
+
+fn cache_key(driver: &str, sources: &[CachedSource]) -> u64 {
+    let mut hash = Fnv::new();
+    hash.write_str(driver);
+    for source in sources {
+        hash.write(&gl::types::GLenum::from(source.stage).to_le_bytes());
+        hash.write_str(source.source);
+    }
+    hash.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::{self, StorageBuffer};
+    use crate::headless::{self, HeadlessContext};
+    use crate::shader::ShaderBuilder;
+
+    fn key(driver: &str, sources: &[(ShaderType, &str)]) -> u64 {
+        let sources: Vec<_> = sources.iter().map(|&(stage, source)| CachedSource { stage, source }).collect();
+        cache_key(driver, &sources)
+    }
+
+    #[test]
+    fn fnv_matches_the_reference_values() {
+        let mut hash = Fnv::new();
+        hash.write(b"a");
+        assert_eq!(hash.0, 0xaf63_dc4c_8601_ec8c);
+        let mut hash = Fnv::new();
+        hash.write(b"foobar");
+        assert_eq!(hash.0, 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn keys_change_with_the_driver_and_every_source() {
+        let program = [(ShaderType::Vertex, "void main() {}"), (ShaderType::Fragment, "void main() {}")];
+        let base = key("NVIDIA\n4.6.0 NVIDIA 535", &program);
+        assert_eq!(base, key("NVIDIA\n4.6.0 NVIDIA 535", &program));
+        assert_ne!(base, key("NVIDIA\n4.6.0 NVIDIA 545", &program));
+        assert_ne!(base, key("NVIDIA\n4.6.0 NVIDIA 535", &[program[0], (ShaderType::Fragment, "void main() { }")]));
+        assert_ne!(base, key("NVIDIA\n4.6.0 NVIDIA 535", &[program[1], program[0]]));
+    }
+
+    #[test]
+    fn sources_do_not_run_into_each_other() {
+        let split_early = key("", &[(ShaderType::Vertex, "ab"), (ShaderType::Vertex, "c")]);
+        let split_late = key("", &[(ShaderType::Vertex, "a"), (ShaderType::Vertex, "bc")]);
+        assert_ne!(split_early, split_late);
+    }
+
+    const DOUBLE: &str = "#version 430 core
+layout(local_size_x = 64) in;
+layout(std430, binding = 0) buffer Values { uint values[]; };
+void main() { values[gl_GlobalInvocationID.x] *= 2u; }
+";
+
+    #[test]
+    fn rejected_binaries_are_built_from_source_and_replaced() {
+        let _context = match headless::unless_skipped("rejected_binaries_are_built_from_source_and_replaced", unsafe { HeadlessContext::new() }) {
+            Some(context) => context,
+            None => return,
+        };
+        let dir = std::env::temp_dir().join(format!("gloom-rs-program-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        unsafe {
+            let cache = ProgramCache::new(dir.to_str().unwrap());
+            let key = cache.key(&[CachedSource { stage: ShaderType::Compute, source: DOUBLE }]);
+            let garbage = [0x2a, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(cache.path(key), garbage).unwrap();
+
+            let program = ShaderBuilder::new()
+                .cache(&cache)
+                .compile_shader(DOUBLE, ShaderType::Compute).unwrap()
+                .link().unwrap();
+            let buffer = StorageBuffer::new(&[1u32; 64], gl::DYNAMIC_COPY);
+            buffer.bind(0);
+            program.dispatch([1, 1, 1]).unwrap();
+            gpu::memory_barrier(gl::BUFFER_UPDATE_BARRIER_BIT);
+            assert_eq!(buffer.read::<u32>(), vec![2u32; 64]);
+
+            // The garbage was replaced by a binary which the driver takes, if it can save any
+            let mut formats = 0;
+            gl::GetIntegerv(gl::NUM_PROGRAM_BINARY_FORMATS, &mut formats);
+            if formats > 0 {
+                assert_ne!(std::fs::read(cache.path(key)).unwrap(), garbage);
+                let program_id = gl::CreateProgram();
+                assert!(cache.load(key, program_id));
+                gl::DeleteProgram(program_id);
+            }
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
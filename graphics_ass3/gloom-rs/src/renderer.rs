@@ -0,0 +1,243 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::HashMap;
+use std::ptr;
+use std::rc::Weak;
+
+use crate::debug_output;
+use crate::gpu::{GpuMesh, VertexArray, VertexAttribute, VertexBuffer, VertexLayout};
+use crate::scene_graph::{SceneGraph, NodeId};
+use crate::shader::Shader;
+use crate::shader_reflection::UniformError;
+use crate::uniform_buffer::{Std140, Std140Type, Std140Writer};
+
+// Draws a scene graph. Nodes which share a GpuMesh are drawn together with a single instanced draw
+// call, with their matrices in a per-instance vertex buffer. Everything else, including nodes which
+// only have a raw vao_id, is drawn one node at a time with the matrices as uniforms.
+
+// The first attribute location used for per-instance data. The mesh itself uses 0 to 3
+pub const FIRST_INSTANCE_LOCATION: u32 = 6;
+
+// One per-instance input of the vertex shader. Matrices take up one location per column
+struct InstanceInput {
+    name    : &'static str,
+    columns : u32,
+    rows    : i32,
+}
+
+// Has to match the fields of Instance, in the same order
+const INSTANCE_INPUTS: [InstanceInput; 2] = [
+    InstanceInput { name: "instance_model",  columns: 4, rows: 4 },
+    InstanceInput { name: "instance_normal", columns: 3, rows: 3 },
+];
+
+// The per-instance data of one node, as it is laid out in the instance buffer
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Instance {
+    pub model  : [f32; 16],          // Column major, like glm
+    pub normal : [f32; 9],
+}
+
+impl Instance {
+    pub fn new(model: &glm::Mat4, normal: &glm::Mat3) -> Instance {
+        let mut instance = Instance { model: [0.0; 16], normal: [0.0; 9] };
+        instance.model.copy_from_slice(model.as_slice());
+        instance.normal.copy_from_slice(normal.as_slice());
+        instance
+    }
+
+    pub fn layout() -> VertexLayout {
+        let mut location = FIRST_INSTANCE_LOCATION;
+        let mut attributes = vec![];
+        for input in &INSTANCE_INPUTS {
+            for _ in 0..input.columns {
+                attributes.push(VertexAttribute::floats(location, input.rows).per_instance());
+                location += 1;
+            }
+        }
+        VertexLayout { attributes }
+    }
+}
+
+// The declarations the vertex shader needs to read the instance buffer, generated from the same
+// table as the layout so that the two can not get out of sync. Pass it to attach_file_with_prelude.
+pub fn instancing_prelude() -> String {
+    let mut prelude = String::from("#define INSTANCED 1\n");
+    let mut location = FIRST_INSTANCE_LOCATION;
+    for input in &INSTANCE_INPUTS {
+        let glsl_type = if input.columns as i32 == input.rows {
+            format!("mat{}", input.columns)
+        } else {
+            format!("mat{}x{}", input.columns, input.rows)
+        };
+        prelude += &format!("in layout(location={}) {} {};\n", location, glsl_type, input.name);
+        location += input.columns;
+    }
+    prelude
+}
+
+// The binding point of the Frame uniform block in shaders/frame.glsl
+pub const FRAME_BINDING: u32 = 0;
+
+// The Frame uniform block, filled once per frame and shared by every program
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameUniforms {
+    pub view_projection : glm::Mat4,
+    pub light_direction : glm::Vec3,
+    pub time            : f32,
+}
+
+impl Std140 for FrameUniforms {
+    fn std140_type() -> Std140Type {
+        Std140Type::Struct(vec![Std140Type::Mat4, Std140Type::Vec3, Std140Type::Float])
+    }
+
+    fn write(&self, writer: &mut Std140Writer) {
+        writer.push(&self.view_projection);
+        writer.push(&self.light_direction);
+        writer.push(&self.time);
+    }
+}
+
+// All the nodes drawing the same mesh this frame
+struct Batch {
+    mesh      : GpuMesh,
+    nodes     : Vec<NodeId>,
+}
+
+pub struct Renderer {
+    pub min_instances : usize,       // Smaller batches are drawn one node at a time
+    instance_buffers  : Vec<(Weak<VertexArray>, VertexBuffer)>,
+}
+
+// What the last frame cost, to be able to compare the two ways of drawing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    pub draw_calls : usize,
+    pub instanced  : usize,          // Nodes drawn by instanced draw calls
+    pub single     : usize,          // Nodes drawn one at a time
+}
+
+impl Default for Renderer {
+    fn default() -> Renderer {
+        Renderer { min_instances: 2, instance_buffers: vec![] }
+    }
+}
+
+impl Renderer {
+    pub fn new() -> Renderer {
+        Renderer::default()
+    }
+
+    // Both shaders should be built from the same source, with the instanced one compiled with
+    // instancing_prelude(), and have the FrameUniforms for this frame bound already. If a uniform
+    // is missing from one of them, everything is still drawn as well as it can be, and the first
+    // such error is returned.
+    pub unsafe fn draw(&mut self, scene: &SceneGraph, single: &Shader, instanced: &Shader) -> Result<FrameStats, UniformError> {
+        let mut stats = FrameStats::default();
+        let mut error = None;
+        let (batches, unique) = self.batch(scene);
+        let min_instances = self.min_instances;
+
+        // Forget the instance buffers of meshes which have been deleted
+        self.instance_buffers.retain(|(mesh, _)| mesh.strong_count() > 0);
+
+        single.activate();
+        let small_batches = batches.iter().filter(|b| b.nodes.len() < min_instances);
+        for &id in unique.iter().chain(small_batches.flat_map(|b| &b.nodes)) {
+            let node = &scene[id];
+            keep_first(&mut error, single.set_mat4("M_Mod", &node.current_transformation_matrix));
+            keep_first(&mut error, single.set_mat3("M_Normal", &node.normal_matrix()));
+            gl::BindVertexArray(node.vao_id);
+            gl::DrawElements(gl::TRIANGLES, node.index_count, gl::UNSIGNED_INT, ptr::null());
+            stats.draw_calls += 1;
+            stats.single += 1;
+        }
+
+        instanced.activate();
+        for batch in batches.iter().filter(|b| b.nodes.len() >= min_instances) {
+            let instances: Vec<Instance> = batch.nodes.iter()
+                .map(|&id| Instance::new(&scene[id].current_transformation_matrix, &scene[id].normal_matrix()))
+                .collect();
+            self.upload_instances(&batch.mesh, &instances);
+            gl::BindVertexArray(batch.mesh.vao_id());
+            gl::DrawElementsInstanced(gl::TRIANGLES, batch.mesh.index_count(), gl::UNSIGNED_INT, ptr::null(), instances.len() as i32);
+            stats.draw_calls += 1;
+            stats.instanced += instances.len();
+        }
+
+        gl::BindVertexArray(0);
+        error.map_or(Ok(stats), Err)
+    }
+
+    // Groups the drawable nodes by mesh, in the order they are found. Nodes without a GpuMesh
+    // can not be instanced, and are returned on their own.
+    fn batch(&self, scene: &SceneGraph) -> (Vec<Batch>, Vec<NodeId>) {
+        let mut batches: Vec<Batch> = vec![];
+        let mut batch_of_vao = HashMap::new();
+        let mut unique = vec![];
+        for id in scene.find_all(|node| node.index_count >= 0) {
+            match &scene[id].gpu_mesh {
+                Some(mesh) => {
+                    let i = *batch_of_vao.entry(mesh.vao_id()).or_insert_with(|| {
+                        batches.push(Batch { mesh: mesh.clone(), nodes: vec![] });
+                        batches.len() - 1
+                    });
+                    batches[i].nodes.push(id);
+                },
+                None => unique.push(id),
+            }
+        }
+        (batches, unique)
+    }
+
+    // The first time a mesh is instanced, its vertex array gets a buffer of its own for the
+    // instance data. After that, the buffer is only refilled.
+    unsafe fn upload_instances(&mut self, mesh: &GpuMesh, instances: &[Instance]) {
+        match self.instance_buffers.iter_mut().find(|(m, _)| mesh.is(m)) {
+            Some((_, buffer)) => buffer.update(instances, gl::STREAM_DRAW),
+            None => {
+                let buffer = VertexBuffer::new(instances, gl::STREAM_DRAW);
+                debug_output::label(debug_output::Object::Buffer, buffer.id(), &format!("Instances of vertex array {}", mesh.vao_id()));
+                mesh.vertex_array().attach_buffer(&buffer, &Instance::layout());
+                self.instance_buffers.push((mesh.downgrade(), buffer));
+            },
+        }
+    }
+}
+
+fn keep_first(first: &mut Option<UniformError>, result: Result<(), UniformError>) {
+    if let Err(e) = result {
+        first.get_or_insert(e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instance_layout_matches_the_struct() {
+        let layout = Instance::layout();
+        assert_eq!(layout.stride() as usize, std::mem::size_of::<Instance>());
+        assert_eq!(layout.attributes.len(), 7);
+        assert!(layout.attributes.iter().all(|a| a.divisor == 1));
+        assert_eq!(layout.offsets()[4] as usize, std::mem::offset_of!(Instance, normal));
+    }
+
+    #[test]
+    fn frame_uniforms_match_frame_glsl() {
+        assert_eq!(Std140Type::offsets(&[Std140Type::Mat4, Std140Type::Vec3, Std140Type::Float]), vec![0, 64, 76]);
+        assert_eq!(FrameUniforms::std140_type().size(), 80);
+    }
+
+    #[test]
+    fn prelude_declares_every_location_of_the_layout() {
+        let prelude = instancing_prelude();
+        assert!(prelude.contains("in layout(location=6) mat4 instance_model;"));
+        assert!(prelude.contains("in layout(location=10) mat3 instance_normal;"));
+        let last = Instance::layout().attributes.last().unwrap().location;
+        assert_eq!(last, 12);
+    }
+}
@@ -0,0 +1,223 @@
+extern crate nalgebra_glm as glm;
+
+use serde::{Serialize, Deserialize};
+
+// Helpers for working with orientations as quaternions, while still being able to think about
+// them as the Euler angles (roll, pitch and yaw) that are much easier to reason about.
+
+// The order in which the three Euler angles are applied to a vertex.
+// ZYX rotates about Z first, then Y, then X, giving the matrix Rx * Ry * Rz.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EulerOrder {
+    XYZ,
+    XZY,
+    YXZ,
+    YZX,
+    ZXY,
+    #[default]
+    ZYX,
+}
+
+const UNIT_AXES: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+impl EulerOrder {
+    // The axes in the order they are applied
+    pub fn axes(self) -> [usize; 3] {
+        match self {
+            EulerOrder::XYZ => [0, 1, 2],
+            EulerOrder::XZY => [0, 2, 1],
+            EulerOrder::YXZ => [1, 0, 2],
+            EulerOrder::YZX => [1, 2, 0],
+            EulerOrder::ZXY => [2, 0, 1],
+            EulerOrder::ZYX => [2, 1, 0],
+        }
+    }
+
+    // Angles are given per axis, i.e. angles.x is always the rotation about the X axis
+    pub fn to_quat(self, angles: &glm::Vec3) -> glm::Quat {
+        self.axes().iter().fold(glm::quat_identity(), |orientation, &axis| {
+            from_axis_angle(&UNIT_AXES[axis].into(), angles[axis]) * orientation
+        })
+    }
+
+    // The inverse of to_quat. The middle angle is kept within [-pi/2, pi/2], and when it reaches
+    // either end (gimbal lock) the first applied angle is set to zero.
+    pub fn angles_of(self, orientation: &glm::Quat) -> glm::Vec3 {
+        let m = glm::quat_to_mat3(orientation);
+        let [k, j, i] = self.axes();
+        // The formulas below are written for Ri * Rj * Rk with (i, j, k) = (x, y, z); odd
+        // permutations of the axes flip the signs of the off-diagonal terms
+        let s = if (i + 1) % 3 == j { 1.0 } else { -1.0 };
+
+        let mut angles = glm::Vec3::zeros();
+        let sin_j = (s * m[(i, k)]).clamp(-1.0, 1.0);
+        // Near +-1, asin loses most of its precision, while the cosine from the rest of the row does not
+        let cos_j = (m[(i, i)] * m[(i, i)] + m[(i, j)] * m[(i, j)]).sqrt();
+        angles[j] = sin_j.atan2(cos_j);
+        if sin_j.abs() < 0.999_999 {
+            angles[i] = (-s * m[(j, k)]).atan2(m[(k, k)]);
+            angles[k] = (-s * m[(i, j)]).atan2(m[(i, i)]);
+        } else {
+            angles[i] = (s * m[(k, j)]).atan2(m[(j, j)]);
+            angles[k] = 0.0;
+        }
+        angles
+    }
+}
+
+// A rotation of `angle` radians about `axis`, which does not have to be normalized
+pub fn from_axis_angle(axis: &glm::Vec3, angle: f32) -> glm::Quat {
+    glm::quat_angle_axis(angle, &glm::normalize(axis))
+}
+
+// The orientation which points the local -Z axis along `forward`, with the local +Y axis as close
+// to `up` as possible. This matches the OpenGL convention of looking down the negative Z axis.
+pub fn look_rotation(forward: &glm::Vec3, up: &glm::Vec3) -> glm::Quat {
+    let z = -glm::normalize(forward);
+    let mut x = glm::cross(up, &z);
+    if glm::length2(&x) < 1e-12 {
+        // `up` is parallel to `forward`, so any perpendicular axis will have to do
+        x = glm::cross(&glm::vec3(1.0, 0.0, 0.0), &z);
+        if glm::length2(&x) < 1e-12 {
+            x = glm::cross(&glm::vec3(0.0, 0.0, 1.0), &z);
+        }
+    }
+    let x = glm::normalize(&x);
+    let y = glm::cross(&z, &x);
+    glm::mat3_to_quat(&glm::mat3(
+        x.x, y.x, z.x,
+        x.y, y.y, z.y,
+        x.z, y.z, z.z,
+    ))
+}
+
+// Spherical linear interpolation, always taking the shortest way around
+#[cfg_attr(not(test), allow(dead_code))] // The animations set the orientation outright, nothing blends between two yet
+pub fn slerp(from: &glm::Quat, to: &glm::Quat, t: f32) -> glm::Quat {
+    let to = shortest_path_to(from, to);
+    let cos_theta = glm::quat_dot(from, &to);
+    if cos_theta > 0.9995 {
+        // Too close for the division by sin(theta) to be accurate, but then nlerp is just as good
+        return glm::quat_normalize(&glm::quat_lerp(from, &to, t));
+    }
+    glm::quat_normalize(&glm::quat_slerp(from, &to, t))
+}
+
+// Normalized linear interpolation. Cheaper than slerp, but does not move at a constant speed
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn nlerp(from: &glm::Quat, to: &glm::Quat, t: f32) -> glm::Quat {
+    let to = shortest_path_to(from, to);
+    glm::quat_normalize(&glm::quat_lerp(from, &to, t))
+}
+
+// q and -q describe the same orientation, but interpolating towards the wrong one goes the long way
+fn shortest_path_to(from: &glm::Quat, to: &glm::Quat) -> glm::Quat {
+    if glm::quat_dot(from, to) < 0.0 { -to } else { *to }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+
+    const ORDERS: [EulerOrder; 6] = [
+        EulerOrder::XYZ, EulerOrder::XZY, EulerOrder::YXZ,
+        EulerOrder::YZX, EulerOrder::ZXY, EulerOrder::ZYX,
+    ];
+
+    // Compares orientations, which q and -q both are
+    fn assert_same_orientation(actual: &glm::Quat, expected: &glm::Quat) {
+        let difference = (glm::quat_to_mat3(actual) - glm::quat_to_mat3(expected)).abs().max();
+        assert!(difference < 1e-5, "{:?} != {:?}", actual, expected);
+    }
+
+    fn assert_near(actual: glm::Vec3, expected: glm::Vec3) {
+        assert!(glm::distance(&actual, &expected) < 1e-5, "{} != {}", actual, expected);
+    }
+
+    fn about(axis: [f32; 3], angle: f32) -> glm::Quat {
+        from_axis_angle(&axis.into(), angle)
+    }
+
+    #[test]
+    fn euler_angles_apply_in_the_given_order() {
+        let angles = glm::vec3(0.3, -0.7, 1.1);
+        let rotate = |axis: [f32; 3], angle: f32| glm::rotation(angle, &axis.into());
+        let (x, y, z) = (rotate([1.0, 0.0, 0.0], angles.x), rotate([0.0, 1.0, 0.0], angles.y), rotate([0.0, 0.0, 1.0], angles.z));
+        let to_matrix = |order: EulerOrder| glm::quat_to_mat4(&order.to_quat(&angles));
+        assert!((to_matrix(EulerOrder::ZYX) - x * y * z).abs().max() < 1e-5);
+        assert!((to_matrix(EulerOrder::XYZ) - z * y * x).abs().max() < 1e-5);
+        assert!((to_matrix(EulerOrder::YXZ) - z * x * y).abs().max() < 1e-5);
+    }
+
+    #[test]
+    fn euler_angles_survive_the_round_trip() {
+        let angles = glm::vec3(0.3, -0.7, 1.1);
+        for order in ORDERS {
+            let back = order.angles_of(&order.to_quat(&angles));
+            assert_near(back, angles);
+        }
+    }
+
+    #[test]
+    fn gimbal_lock_zeroes_the_first_angle() {
+        for order in ORDERS {
+            let [first, middle, last] = order.axes();
+            for middle_angle in [FRAC_PI_2, -FRAC_PI_2] {
+                let mut angles = glm::Vec3::zeros();
+                angles[first] = 0.4;
+                angles[middle] = middle_angle;
+                angles[last] = 0.3;
+                let orientation = order.to_quat(&angles);
+
+                let back = order.angles_of(&orientation);
+                assert!((back[middle] - middle_angle).abs() < 1e-3, "{:?}: {}", order, back);
+                assert_eq!(back[first], 0.0);
+                // The other two angles are folded into the last one, which still gives the same orientation
+                assert_same_orientation(&order.to_quat(&back), &orientation);
+            }
+        }
+    }
+
+    #[test]
+    fn look_rotation_points_forward_and_keeps_up() {
+        let up = glm::vec3(0.0, 1.0, 0.0);
+        let forward = glm::normalize(&glm::vec3(1.0, 2.0, -3.0));
+        let orientation = look_rotation(&forward, &up);
+        assert_near(glm::quat_rotate_vec3(&orientation, &glm::vec3(0.0, 0.0, -1.0)), forward);
+        // No roll: the right axis stays level, and up stays on the side of the world's up
+        let right = glm::quat_rotate_vec3(&orientation, &glm::vec3(1.0, 0.0, 0.0));
+        assert!(right.y.abs() < 1e-5, "{}", right);
+        assert!(glm::quat_rotate_vec3(&orientation, &up).y > 0.0);
+
+        // Looking straight up still gives some orientation looking that way
+        let orientation = look_rotation(&up, &up);
+        assert_near(glm::quat_rotate_vec3(&orientation, &glm::vec3(0.0, 0.0, -1.0)), up);
+    }
+
+    #[test]
+    fn interpolation_hits_the_endpoints_and_the_middle() {
+        let from = glm::quat_identity();
+        let to = about([0.0, 0.0, 1.0], FRAC_PI_2);
+        for interpolate in [slerp, nlerp] {
+            assert_same_orientation(&interpolate(&from, &to, 0.0), &from);
+            assert_same_orientation(&interpolate(&from, &to, 1.0), &to);
+            assert_same_orientation(&interpolate(&from, &to, 0.5), &about([0.0, 0.0, 1.0], FRAC_PI_4));
+        }
+        // Unlike nlerp, slerp turns at a constant speed
+        assert_same_orientation(&slerp(&from, &to, 0.25), &about([0.0, 0.0, 1.0], FRAC_PI_4 / 2.0));
+    }
+
+    #[test]
+    fn interpolation_takes_the_short_way_around() {
+        let from = glm::quat_identity();
+        let to = -about([0.0, 0.0, 1.0], FRAC_PI_2);
+        assert!(glm::quat_dot(&from, &to) < 0.0);
+        assert_eq!(shortest_path_to(&from, &to), -to);
+        assert_eq!(shortest_path_to(&from, &-to), -to);
+        for interpolate in [slerp, nlerp] {
+            assert_same_orientation(&interpolate(&from, &to, 0.5), &about([0.0, 0.0, 1.0], FRAC_PI_4));
+        }
+    }
+}
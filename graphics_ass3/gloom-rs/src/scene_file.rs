@@ -0,0 +1,334 @@
+extern crate nalgebra_glm as glm;
+
+use std::{fmt, fs, io, path::Path};
+
+use serde::{Serialize, Deserialize};
+
+use crate::rotation::EulerOrder;
+use crate::scene_graph::{NodeId, SceneError, SceneGraph, SceneNode};
+use crate::toolbox::Animation;
+
+// A declarative description of a scene, which can be stored as RON (.ron) or JSON (.json).
+// Meshes are listed once by name, and nodes refer to them by that name. Everything which is left
+// out of a node gets the same default value as SceneNode::new() would give it, so a minimal node
+// only needs a name. Saving a scene back out writes every node in full, and loading that file
+// again gives back exactly the same scene.
+//
+//     (
+//         meshes: [
+//             (name: "terrain", path: "resources/lunarsurface.obj"),
+//         ],
+//         nodes: [
+//             (name: "terrain", mesh: "terrain", position: (0.0, -10.0, 0.0)),
+//         ],
+//     )
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SceneFile {
+    #[serde(default)]
+    pub meshes: Vec<MeshDescription>,
+    #[serde(default)]
+    pub nodes: Vec<NodeDescription>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MeshDescription {
+    pub name   : String,
+    pub path   : String,           // The OBJ file to load
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object : Option<String>,   // Which object in the OBJ file, if there are more than one
+    #[serde(default = "white")]
+    pub colour : [f32; 4],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Rotation {
+    Euler([f32; 3]),          // Angles about each axis, applied in the node's rotation_order
+    Quaternion([f32; 4]),     // (x, y, z, w)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NodeDescription {
+    pub name            : String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags            : Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mesh            : Option<String>,
+    pub position        : [f32; 3],
+    pub rotation        : Rotation,
+    pub rotation_order  : EulerOrder,
+    pub scale           : [f32; 3],
+    pub shear           : [f32; 3],
+    pub reference_point : [f32; 3],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub animation       : Option<Animation>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children        : Vec<NodeDescription>,
+}
+
+impl Default for NodeDescription {
+    fn default() -> Self {
+        NodeDescription {
+            name            : String::new(),
+            tags            : vec![],
+            mesh            : None,
+            position        : [0.0; 3],
+            rotation        : Rotation::Euler([0.0; 3]),
+            rotation_order  : EulerOrder::default(),
+            scale           : [1.0; 3],
+            shear           : [0.0; 3],
+            reference_point : [0.0; 3],
+            animation       : None,
+            children        : vec![],
+        }
+    }
+}
+
+fn white() -> [f32; 4] {
+    [1.0, 1.0, 1.0, 1.0]
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+#[derive(Debug)]
+pub enum SceneFileError {
+    Io(io::Error),
+    Ron(ron::error::SpannedError),
+    RonWrite(ron::Error),
+    Json(serde_json::Error),
+    UnknownFormat(String),      // The file extension, which was neither ron nor json
+    UnknownMesh(String),        // A node referred to a mesh which is not listed
+    MeshLoad(String),           // The mesh could not be loaded or uploaded
+    Scene(SceneError),
+}
+
+impl fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneFileError::Io(e)            => write!(f, "{}", e),
+            SceneFileError::Ron(e)           => write!(f, "Invalid RON scene: {}", e),
+            SceneFileError::RonWrite(e)      => write!(f, "Failed to write RON scene: {}", e),
+            SceneFileError::Json(e)          => write!(f, "Invalid JSON scene: {}", e),
+            SceneFileError::UnknownFormat(e) => write!(f, "Unknown scene file format {:?}, use .ron or .json", e),
+            SceneFileError::UnknownMesh(e)   => write!(f, "No mesh named {:?} in the scene file", e),
+            SceneFileError::MeshLoad(e)      => write!(f, "{}", e),
+            SceneFileError::Scene(e)         => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SceneFileError {}
+
+impl From<io::Error> for SceneFileError {
+    fn from(e: io::Error) -> Self { SceneFileError::Io(e) }
+}
+impl From<SceneError> for SceneFileError {
+    fn from(e: SceneError) -> Self { SceneFileError::Scene(e) }
+}
+
+enum Format {
+    Ron,
+    Json,
+}
+
+impl Format {
+    fn of(path: &Path) -> Result<Format, SceneFileError> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("ron")  => Ok(Format::Ron),
+            Some("json") => Ok(Format::Json),
+            e => Err(SceneFileError::UnknownFormat(e.unwrap_or("").to_string())),
+        }
+    }
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl SceneFile {
+
+    pub fn load(path: &str) -> Result<SceneFile, SceneFileError> {
+        let source = fs::read_to_string(path)?;
+        match Format::of(Path::new(path))? {
+            Format::Ron  => SceneFile::from_ron(&source),
+            Format::Json => serde_json::from_str(&source).map_err(SceneFileError::Json),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), SceneFileError> {
+        let text = match Format::of(Path::new(path))? {
+            Format::Ron  => self.to_ron()?,
+            Format::Json => serde_json::to_string_pretty(self).map_err(SceneFileError::Json)?,
+        };
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    // Optional values may be written without wrapping them in Some(...)
+    pub fn from_ron(source: &str) -> Result<SceneFile, SceneFileError> {
+        ron::Options::default()
+            .with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME)
+            .from_str(source)
+            .map_err(SceneFileError::Ron)
+    }
+
+    pub fn to_ron(&self) -> Result<String, SceneFileError> {
+        let config = ron::ser::PrettyConfig::new()
+            .extensions(ron::extensions::Extensions::IMPLICIT_SOME);
+        ron::ser::to_string_pretty(self, config).map_err(SceneFileError::RonWrite)
+    }
+
+    // Adds the nodes of the file as children of `parent`. For every node with a mesh, `create`
+    // is handed the description of that mesh and should return a node which draws it, i.e. from
+    // SceneNode::from_mesh. Nodes without a mesh start out as SceneNode::new().
+    pub fn build<F>(&self, scene: &mut SceneGraph, parent: NodeId, mut create: F) -> Result<Vec<NodeId>, SceneFileError>
+        where F: FnMut(&MeshDescription) -> Result<SceneNode, String>
+    {
+        let mut top_level = vec![];
+        // (description, parent) pairs still waiting to be added
+        let mut pending: Vec<(&NodeDescription, NodeId)> = self.nodes.iter().rev().map(|n| (n, parent)).collect();
+        while let Some((description, parent_id)) = pending.pop() {
+            let mut node = match &description.mesh {
+                Some(name) => {
+                    let mesh = self.meshes.iter().find(|m| &m.name == name)
+                        .ok_or_else(|| SceneFileError::UnknownMesh(name.to_string()))?;
+                    create(mesh).map_err(SceneFileError::MeshLoad)?
+                },
+                None => SceneNode::new(),
+            };
+            description.apply_to(&mut node);
+            let id = scene.add_child(parent_id, node)?;
+            if parent_id == parent {
+                top_level.push(id);
+            }
+            pending.extend(description.children.iter().rev().map(|c| (c, id)));
+        }
+        Ok(top_level)
+    }
+
+    // Describes every node below `parent` (but not `parent` itself), for saving
+    pub fn capture(scene: &SceneGraph, parent: NodeId, meshes: Vec<MeshDescription>) -> SceneFile {
+        SceneFile {
+            meshes,
+            nodes: scene[parent].children().iter().map(|&id| NodeDescription::capture(scene, id)).collect(),
+        }
+    }
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl NodeDescription {
+
+    fn apply_to(&self, node: &mut SceneNode) {
+        node.name            = self.name.clone();
+        node.tags            = self.tags.clone();
+        node.mesh            = self.mesh.clone();
+        node.position        = self.position.into();
+        node.rotation_order  = self.rotation_order;
+        node.scale           = self.scale.into();
+        node.shear           = self.shear.into();
+        node.reference_point = self.reference_point.into();
+        node.animation       = self.animation;
+        match self.rotation {
+            Rotation::Euler(angles)            => node.set_euler_angles(&angles.into()),
+            Rotation::Quaternion([x, y, z, w]) => node.orientation = glm::quat(x, y, z, w),
+        }
+    }
+
+    fn capture(scene: &SceneGraph, id: NodeId) -> NodeDescription {
+        let node = &scene[id];
+        let q = node.orientation;
+        NodeDescription {
+            name            : node.name.clone(),
+            tags            : node.tags.clone(),
+            mesh            : node.mesh.clone(),
+            position        : node.position.into(),
+            // Quaternions survive the round trip exactly, Euler angles might not
+            rotation        : Rotation::Quaternion([q.i, q.j, q.k, q.w]),
+            rotation_order  : node.rotation_order,
+            scale           : node.scale.into(),
+            shear           : node.shear.into(),
+            reference_point : node.reference_point.into(),
+            animation       : node.animation,
+            children        : node.children().iter().map(|&child| NodeDescription::capture(scene, child)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: &str = r#"(
+        meshes: [
+            (name: "body", path: "resources/helicopter.obj", object: "Body_body", colour: (0.3, 0.3, 0.3, 1.0)),
+            (name: "rotor", path: "resources/helicopter.obj", object: "Main_Rotor_main_rotor"),
+        ],
+        nodes: [
+            (
+                name: "helicopter_0",
+                tags: ["helicopter"],
+                mesh: "body",
+                position: (1.5, 0.1, -3.0),
+                rotation: Euler((0.3, 1.2, -0.7)),
+                animation: Heading(time_offset: 0.8),
+                children: [
+                    (name: "main_rotor", mesh: "rotor", reference_point: (0.35, 2.3, 10.4), scale: (1.0, 2.0, 0.5)),
+                ],
+            ),
+            (name: "empty"),
+        ],
+    )"#;
+
+    // Returns the graph and how many distinct meshes were asked for
+    fn build(file: &SceneFile) -> (SceneGraph, usize) {
+        let mut scene = SceneGraph::new();
+        let mut meshes: Vec<String> = vec![];
+        let root = scene.root();
+        file.build(&mut scene, root, |mesh| {
+            if !meshes.contains(&mesh.name) {
+                meshes.push(mesh.name.clone());
+            }
+            Ok(SceneNode::from_vao(meshes.len() as u32, 3))
+        }).unwrap();
+        (scene, meshes.len())
+    }
+
+    #[test]
+    fn round_trip_is_lossless() {
+        let file = SceneFile::from_ron(SCENE).unwrap();
+        let (scene, uploads) = build(&file);
+        assert_eq!(uploads, 2);
+        assert_eq!(scene[[0, 0]].reference_point, glm::vec3(0.35, 2.3, 10.4));
+
+        let saved = SceneFile::capture(&scene, scene.root(), file.meshes.clone());
+        let ron = std::env::temp_dir().join(format!("gloom-rs-scene-{}.ron", std::process::id()));
+        let json = ron.with_extension("json");
+        saved.save(ron.to_str().unwrap()).unwrap();
+        saved.save(json.to_str().unwrap()).unwrap();
+        let reloaded = SceneFile::load(ron.to_str().unwrap()).unwrap();
+        let from_json = SceneFile::load(json.to_str().unwrap()).unwrap();
+        let _ = (std::fs::remove_file(&ron), std::fs::remove_file(&json));
+        assert_eq!(saved, reloaded);
+        assert_eq!(saved, from_json);
+        assert!(matches!(saved.save("scene.yaml"), Err(SceneFileError::UnknownFormat(e)) if e == "yaml"));
+
+        let (rebuilt, _) = build(&reloaded);
+        assert_eq!(SceneFile::capture(&rebuilt, rebuilt.root(), file.meshes.clone()), saved);
+        assert_eq!(rebuilt[[0]].orientation, scene[[0]].orientation);
+    }
+
+    #[test]
+    fn helicopter_scene_loads() {
+        let file = SceneFile::load("scenes/helicopters.ron").unwrap();
+        let (scene, uploads) = build(&file);
+        assert_eq!(uploads, 5);
+        assert_eq!(scene.find_tagged("helicopter").len(), 5);
+        assert_eq!(scene.find_tagged("rotor").len(), 10);
+    }
+
+    #[test]
+    fn unknown_meshes_are_reported() {
+        let file = SceneFile::from_ron(r#"(nodes: [(name: "a", mesh: "missing")])"#).unwrap();
+        let mut scene = SceneGraph::new();
+        let root = scene.root();
+        let result = file.build(&mut scene, root, |_| Ok(SceneNode::from_vao(1, 3)));
+        assert!(matches!(result, Err(SceneFileError::UnknownMesh(name)) if name == "missing"));
+    }
+}
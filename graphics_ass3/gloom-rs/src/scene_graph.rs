@@ -1,72 +1,170 @@
 extern crate nalgebra_glm as glm;
 
-use std::mem::ManuallyDrop;
-use std::pin::Pin;
-
-// Used to create an unholy abomination upon which you should not cast your gaze. This ended up
-// being a necessity due to wanting to keep the code written by students as "straight forward" as
-// possible. It is very very double plus ungood Rust, and intentionally leaks memory like a sieve.
-// But it works, and you're more than welcome to pretend it doesn't exist! In case you're curious
-// about how it works: It allocates memory on the heap (Box), promises to prevent it from being
-// moved or deallocated until dropped (Pin) and finally prevents the compiler from dropping it
-// automatically at all (ManuallyDrop).
-// ...
-// If that sounds like a janky solution, it's because it is!
-// Prettier, Rustier and better solutions were tried numerous times, but were all found wanting of
-// having what I arbitrarily decided to be the required level of "simplicity of use".
-pub type Node = ManuallyDrop<Pin<Box<SceneNode>>>;
+use std::fmt;
+
+use crate::rotation::EulerOrder;
+use crate::toolbox::Animation;
+use crate::gpu::GpuMesh;
+
+// The scene graph owns all of its nodes in a single arena (a Vec of slots). Nodes refer to each
+// other through NodeId handles instead of pointers, which means the whole tree is dropped together
+// with the SceneGraph, and nodes removed from the tree are dropped right away.
+// Every slot carries a generation counter which is bumped whenever the slot is freed, so a handle
+// to a removed node is detected as stale instead of silently pointing at whatever reused the slot.
+//
+// The graph also keeps track of which world matrices are out of date. Any mutable access to a node
+// through the graph (scene[id], scene[[0, 1]], get_mut) marks it and its whole subtree as dirty,
+// and update_transformations only recomputes those subtrees. A dirty node therefore always has
+// dirty descendants, and a clean node only has clean ancestors.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId {
+    index      : u32,
+    generation : u32,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+#[derive(Debug, PartialEq, Eq)]
+pub enum SceneError {
+    StaleHandle(NodeId),          // The node has been removed from the graph
+    NotAChild(NodeId, NodeId),    // (parent, child)
+    WouldCreateCycle(NodeId),     // Tried to make a node a descendant of itself
+    CannotMoveRoot,
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::StaleHandle(id)         => write!(f, "{:?} does not refer to a live node", id),
+            SceneError::NotAChild(parent, child) => write!(f, "{:?} is not a child of {:?}", child, parent),
+            SceneError::WouldCreateCycle(id)    => write!(f, "{:?} cannot become a descendant of itself", id),
+            SceneError::CannotMoveRoot          => write!(f, "the root node cannot be removed or reparented"),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
 
 pub struct SceneNode {
+    pub name            : String,      // What I am called by my parent
+    pub tags            : Vec<String>, // What sort of thing I am
+
     pub position        : glm::Vec3,   // Where I am in relation to my parent
-    pub rotation        : glm::Vec3,   // How I should be rotated
+    pub orientation     : glm::Quat,   // How I should be rotated
     pub scale           : glm::Vec3,   // How I should be scaled
-    pub reference_point : glm::Vec3,   // About which point I shall rotate about
+    pub shear           : glm::Vec3,   // How I should be sheared: x by y, x by z and y by z
+    pub reference_point : glm::Vec3,   // About which point I shall rotate, scale and shear
+    pub rotation_order  : EulerOrder,  // How my orientation is split into Euler angles
 
     pub current_transformation_matrix: glm::Mat4, // The fruits of my labor
 
     pub vao_id      : u32,             // What I should draw
     pub index_count : i32,             // How much of it I shall draw
+    pub mesh        : Option<String>,  // The name of the mesh behind my VAO, if I came from a scene file
+    pub gpu_mesh    : Option<GpuMesh>, // Keeps my VAO alive for as long as I need it
 
-    pub children: Vec<*mut SceneNode>, // Those I command
+    pub animation   : Option<Animation>, // How I move by myself
+
+    parent   : Option<NodeId>,         // The one I answer to
+    children : Vec<NodeId>,            // Those I command
+    dirty    : bool,                   // Whether my current transformation is out of date
 }
 
+#[cfg_attr(not(test), allow(dead_code))]
 impl SceneNode {
 
-    pub fn new() -> Node {
-        ManuallyDrop::new(Pin::new(Box::new(SceneNode {
-            position        : glm::zero(),
-            rotation        : glm::zero(),
-            scale           : glm::vec3(1.0, 1.0, 1.0),
-            reference_point : glm::zero(),
-            current_transformation_matrix: glm::identity(),
-            vao_id          : 0,
-            index_count     : -1,
-            children        : vec![],
-        })))
+    pub fn new() -> SceneNode {
+        SceneNode::from_vao(0, -1)
     }
 
-    pub fn from_vao(vao_id: u32, index_count: i32) -> Node {
-        ManuallyDrop::new(Pin::new(Box::new(SceneNode {
+    pub fn from_vao(vao_id: u32, index_count: i32) -> SceneNode {
+        SceneNode {
+            name            : String::new(),
+            tags            : vec![],
             position        : glm::zero(),
-            rotation        : glm::zero(),
+            orientation     : glm::quat_identity(),
             scale           : glm::vec3(1.0, 1.0, 1.0),
+            shear           : glm::zero(),
             reference_point : glm::zero(),
+            rotation_order  : EulerOrder::default(),
             current_transformation_matrix: glm::identity(),
             vao_id,
             index_count,
-            children: vec![],
-        })))
+            mesh            : None,
+            gpu_mesh        : None,
+            animation       : None,
+            parent          : None,
+            children        : vec![],
+            dirty           : true,
+        }
+    }
+
+    // Draw a shared mesh, which stays uploaded for as long as any node uses it
+    pub fn from_mesh(gpu_mesh: &GpuMesh) -> SceneNode {
+        let mut node = SceneNode::from_vao(gpu_mesh.vao_id(), gpu_mesh.index_count());
+        node.gpu_mesh = Some(gpu_mesh.clone());
+        node
+    }
+
+    // Scenes are read from files, these are for building one in code, like the tests do
+    pub fn with_name(mut self, name: &str) -> SceneNode {
+        self.name = name.to_string();
+        self
     }
 
-    pub fn add_child(&mut self, child: &SceneNode) {
-        self.children.push(child as *const SceneNode as *mut SceneNode)
+    pub fn with_tag(mut self, tag: &str) -> SceneNode {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    // The transformation from my own coordinate system to my parent's. Vertices are sheared, scaled
+    // and rotated about the reference point, and then moved by the position:
+    //     T(position) * T(reference) * R * S * H * T(-reference)
+    pub fn local_transformation(&self) -> glm::Mat4 {
+        let shear = glm::mat4(
+            1.0, self.shear.x, self.shear.y, 0.0,
+            0.0, 1.0,          self.shear.z, 0.0,
+            0.0, 0.0,          1.0,          0.0,
+            0.0, 0.0,          0.0,          1.0,
+        );
+        glm::translation(&self.position)
+            * glm::translation(&self.reference_point)
+            * glm::quat_to_mat4(&self.orientation)
+            * glm::scaling(&self.scale)
+            * shear
+            * glm::translation(&-self.reference_point)
+    }
+
+    // My orientation as Euler angles about each axis, applied in my rotation_order
+    pub fn euler_angles(&self) -> glm::Vec3 {
+        self.rotation_order.angles_of(&self.orientation)
+    }
+
+    pub fn set_euler_angles(&mut self, angles: &glm::Vec3) {
+        self.orientation = self.rotation_order.to_quat(angles);
+    }
+
+    // The matrix to transform my normals with, which unlike mat3(M) stays correct when I am scaled
+    // non-uniformly or sheared
+    pub fn normal_matrix(&self) -> glm::Mat3 {
+        normal_matrix(&self.current_transformation_matrix)
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
     }
 
     #[allow(dead_code)]
-    pub fn get_child(&mut self, index: usize) -> &mut SceneNode {
-        unsafe {
-            &mut (*self.children[index])
-        }
+    pub fn get_child(&self, index: usize) -> NodeId {
+        self.children[index]
     }
 
     #[allow(dead_code)]
@@ -77,13 +175,17 @@ impl SceneNode {
     #[allow(dead_code)]
     pub fn print(&self) {
         let m = self.current_transformation_matrix;
+        let rotation = self.euler_angles();
         println!(
 "SceneNode {{
+    Name:      {:?} {:?}
     VAO:       {}
     Indices:   {}
     Children:  {}
     Position:  [{:.2}, {:.2}, {:.2}]
-    Rotation:  [{:.2}, {:.2}, {:.2}]
+    Rotation:  [{:.2}, {:.2}, {:.2}] ({:?})
+    Scale:     [{:.2}, {:.2}, {:.2}]
+    Shear:     [{:.2}, {:.2}, {:.2}]
     Reference: [{:.2}, {:.2}, {:.2}]
     Current Transformation Matrix:
         {:.2}  {:.2}  {:.2}  {:.2}
@@ -91,15 +193,24 @@ impl SceneNode {
         {:.2}  {:.2}  {:.2}  {:.2}
         {:.2}  {:.2}  {:.2}  {:.2}
 }}",
+            self.name,
+            self.tags,
             self.vao_id,
             self.index_count,
             self.children.len(),
             self.position.x,
             self.position.y,
             self.position.z,
-            self.rotation.x,
-            self.rotation.y,
-            self.rotation.z,
+            rotation.x,
+            rotation.y,
+            rotation.z,
+            self.rotation_order,
+            self.scale.x,
+            self.scale.y,
+            self.scale.z,
+            self.shear.x,
+            self.shear.y,
+            self.shear.z,
             self.reference_point.x,
             self.reference_point.y,
             self.reference_point.z,
@@ -112,21 +223,588 @@ impl SceneNode {
 
 }
 
+impl Default for SceneNode {
+    fn default() -> Self {
+        SceneNode::new()
+    }
+}
+
+// The inverse transpose of the upper 3x3 part of a model matrix
+pub fn normal_matrix(model: &glm::Mat4) -> glm::Mat3 {
+    glm::inverse_transpose(glm::mat4_to_mat3(model))
+}
+
+struct Slot {
+    generation : u32,
+    node       : Option<SceneNode>,
+}
+
+pub struct SceneGraph {
+    slots       : Vec<Slot>,
+    free        : Vec<u32>,      // Indices of empty slots, ready to be reused
+    root        : NodeId,
+    dirty_roots : Vec<NodeId>,   // Nodes which were marked dirty while their parent was clean
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl SceneGraph {
+
+    pub fn new() -> SceneGraph {
+        let mut graph = SceneGraph {
+            slots       : vec![],
+            free        : vec![],
+            root        : NodeId { index: 0, generation: 0 },
+            dirty_roots : vec![],
+        };
+        graph.root = graph.insert(SceneNode::new());
+        graph.dirty_roots.push(graph.root);
+        graph
+    }
+
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
+
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<&SceneNode> {
+        match self.slots.get(id.index as usize) {
+            Some(slot) if slot.generation == id.generation => slot.node.as_ref(),
+            _ => None,
+        }
+    }
+
+    // Mutable access to a node. As the node may be moved around, it is marked as dirty.
+    pub fn get_mut(&mut self, id: NodeId) -> Option<&mut SceneNode> {
+        if !self.contains(id) {
+            return None;
+        }
+        self.mark_dirty(id);
+        self.node_mut(id)
+    }
+
+    // Mutable access which leaves the dirty flags alone, for changes that do not move anything
+    fn node_mut(&mut self, id: NodeId) -> Option<&mut SceneNode> {
+        match self.slots.get_mut(id.index as usize) {
+            Some(slot) if slot.generation == id.generation => slot.node.as_mut(),
+            _ => None,
+        }
+    }
+
+    // Flag the node and everything below it as needing new transformation matrices
+    pub fn mark_dirty(&mut self, id: NodeId) {
+        match self.get(id) {
+            Some(node) if !node.dirty => self.dirty_roots.push(id),
+            _ => return,
+        }
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            let node = self.node_mut(id).unwrap();
+            node.dirty = true;
+            // Children which are already dirty have dirty subtrees as well
+            let children = node.children.clone();
+            stack.extend(children.into_iter().filter(|&child| !self[child].dirty));
+        }
+    }
+
+    // Recomputes the transformation matrices of every dirty node, returning how many were updated
+    pub fn update_transformations(&mut self) -> usize {
+        let mut updated = 0;
+        for id in std::mem::take(&mut self.dirty_roots) {
+            // The node may have been removed, cleaned by world_matrix, or have gotten a dirty
+            // ancestor since it was marked; in all cases we start from the topmost dirty node
+            let top = match self.topmost_dirty(id) {
+                Some(top) => top,
+                None => continue,
+            };
+            let parent_matrix = self.parent_matrix(top);
+            updated += self.update_subtree(top, &parent_matrix);
+        }
+        updated
+    }
+
+    // The node's transformation from its own coordinates to world coordinates. Stale ancestors are
+    // brought up to date on the way, while the rest of the graph is left for update_transformations.
+    pub fn world_matrix(&mut self, id: NodeId) -> Option<glm::Mat4> {
+        let mut stale = vec![];
+        let mut current = Some(id);
+        while let Some(node_id) = current {
+            let node = self.get(node_id)?;
+            if !node.dirty {
+                break;
+            }
+            stale.push(node_id);
+            current = node.parent;
+        }
+
+        let mut matrix = match current {
+            Some(clean) => self[clean].current_transformation_matrix,
+            None => glm::identity(),
+        };
+        while let Some(node_id) = stale.pop() {
+            let next_on_path = stale.last().copied();
+            let node = self.node_mut(node_id).unwrap();
+            matrix *= node.local_transformation();
+            node.current_transformation_matrix = matrix;
+            node.dirty = false;
+            // The siblings of the next node on the path are still dirty, but now have a clean parent
+            let left_behind: Vec<NodeId> = node.children.iter().copied().filter(|&c| Some(c) != next_on_path).collect();
+            self.dirty_roots.extend(left_behind);
+        }
+        Some(matrix)
+    }
+
+    pub fn world_position(&mut self, id: NodeId) -> Option<glm::Vec3> {
+        self.world_matrix(id).map(|m| m.column(3).xyz())
+    }
+
+    fn topmost_dirty(&self, id: NodeId) -> Option<NodeId> {
+        let mut top = None;
+        let mut current = Some(id);
+        while let Some(node_id) = current {
+            match self.get(node_id) {
+                Some(node) if node.dirty => {
+                    top = Some(node_id);
+                    current = node.parent;
+                },
+                _ => break,
+            }
+        }
+        top
+    }
+
+    fn parent_matrix(&self, id: NodeId) -> glm::Mat4 {
+        match self[id].parent {
+            Some(parent) => self[parent].current_transformation_matrix,
+            None => glm::identity(),
+        }
+    }
+
+    fn update_subtree(&mut self, id: NodeId, transformation_so_far: &glm::Mat4) -> usize {
+        let node = self.node_mut(id).unwrap();
+        if !node.dirty {
+            return 0;
+        }
+        let matrix = transformation_so_far * node.local_transformation();
+        node.current_transformation_matrix = matrix;
+        node.dirty = false;
+
+        let mut updated = 1;
+        for child in node.children.clone() {
+            updated += self.update_subtree(child, &matrix);
+        }
+        updated
+    }
+
+    // Follow a list of child indices from the root, i.e. [0, 2] is the third child of the first child
+    pub fn find_path(&self, path: &[usize]) -> Option<NodeId> {
+        let mut id = self.root;
+        for &index in path {
+            id = *self.get(id)?.children.get(index)?;
+        }
+        Some(id)
+    }
+
+    // Looks up a node by the names along its path, separated by slashes. A path starting with a
+    // slash is followed from the root, otherwise the first name is searched for anywhere in the
+    // graph, so "helicopter_3/main_rotor" finds the main rotor of whichever node is helicopter_3.
+    pub fn find(&self, path: &str) -> Option<NodeId> {
+        let mut names = path.split('/').filter(|name| !name.is_empty());
+        let mut id = if path.starts_with('/') {
+            self.root
+        } else {
+            let first = names.next()?;
+            *self.find_all(|node| node.name == first).first()?
+        };
+        for name in names {
+            id = *self[id].children.iter().find(|&&child| self[child].name == name)?;
+        }
+        Some(id)
+    }
+
+    // Every node, from the root down in depth first order, that satisfies the predicate
+    pub fn find_all<P: Fn(&SceneNode) -> bool>(&self, predicate: P) -> Vec<NodeId> {
+        let mut found = vec![];
+        let mut stack = vec![self.root];
+        while let Some(id) = stack.pop() {
+            let node = &self[id];
+            if predicate(node) {
+                found.push(id);
+            }
+            stack.extend(node.children.iter().rev());
+        }
+        found
+    }
+
+    pub fn find_tagged(&self, tag: &str) -> Vec<NodeId> {
+        self.find_all(|node| node.has_tag(tag))
+    }
+
+    // The names from the root down to the node, in the format understood by find
+    pub fn path_of(&self, id: NodeId) -> Option<String> {
+        let mut names = vec![];
+        let mut current = id;
+        while let Some(parent) = self.get(current)?.parent {
+            names.push(self[current].name.as_str());
+            current = parent;
+        }
+        names.reverse();
+        Some(format!("/{}", names.join("/")))
+    }
+
+    // Inserts the node into the graph as the last child of `parent`, returning its handle
+    pub fn add_child(&mut self, parent: NodeId, mut node: SceneNode) -> Result<NodeId, SceneError> {
+        if !self.contains(parent) {
+            return Err(SceneError::StaleHandle(parent));
+        }
+        // A node enters the graph without any children; they have to be added through the graph
+        node.children.clear();
+        node.parent = Some(parent);
+        node.dirty = true;
+        let id = self.insert(node);
+        self.node_mut(parent).unwrap().children.push(id);
+        self.dirty_roots.push(id);
+        Ok(id)
+    }
+
+    // Detaches the child from its parent and drops it along with all of its descendants
+    pub fn remove_child(&mut self, parent: NodeId, child: NodeId) -> Result<(), SceneError> {
+        self.detach(parent, child)?;
+        let mut doomed = vec![child];
+        while let Some(id) = doomed.pop() {
+            let slot = &mut self.slots[id.index as usize];
+            if let Some(node) = slot.node.take() {
+                slot.generation = slot.generation.wrapping_add(1);
+                self.free.push(id.index);
+                doomed.extend(node.children);
+            }
+        }
+        Ok(())
+    }
+
+    // Removes a node from wherever it is in the tree
+    pub fn remove(&mut self, id: NodeId) -> Result<(), SceneError> {
+        let parent = self.get(id).ok_or(SceneError::StaleHandle(id))?.parent.ok_or(SceneError::CannotMoveRoot)?;
+        self.remove_child(parent, id)
+    }
+
+    // Moves a node (and its subtree) to become the last child of `new_parent`
+    pub fn reparent(&mut self, id: NodeId, new_parent: NodeId) -> Result<(), SceneError> {
+        let old_parent = self.get(id).ok_or(SceneError::StaleHandle(id))?.parent.ok_or(SceneError::CannotMoveRoot)?;
+        if !self.contains(new_parent) {
+            return Err(SceneError::StaleHandle(new_parent));
+        }
+        if self.is_ancestor_of(id, new_parent) {
+            return Err(SceneError::WouldCreateCycle(id));
+        }
+        self.detach(old_parent, id)?;
+        self.node_mut(id).unwrap().parent = Some(new_parent);
+        self.node_mut(new_parent).unwrap().children.push(id);
+        self.mark_dirty(id);
+        // A node which was already dirty was left out by mark_dirty, and the update of its old
+        // dirty ancestor would no longer reach it, so it has to be picked up from here
+        self.dirty_roots.push(id);
+        Ok(())
+    }
+
+    // Is `ancestor` equal to `id` or found somewhere above it in the tree?
+    pub fn is_ancestor_of(&self, ancestor: NodeId, id: NodeId) -> bool {
+        let mut current = Some(id);
+        while let Some(node_id) = current {
+            if node_id == ancestor {
+                return true;
+            }
+            current = self.get(node_id).and_then(|node| node.parent);
+        }
+        false
+    }
+
+    fn insert(&mut self, node: SceneNode) -> NodeId {
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.node = Some(node);
+                NodeId { index, generation: slot.generation }
+            },
+            None => {
+                self.slots.push(Slot { generation: 0, node: Some(node) });
+                NodeId { index: self.slots.len() as u32 - 1, generation: 0 }
+            },
+        }
+    }
+
+    fn detach(&mut self, parent: NodeId, child: NodeId) -> Result<(), SceneError> {
+        let siblings = &mut self.node_mut(parent).ok_or(SceneError::StaleHandle(parent))?.children;
+        let position = siblings.iter().position(|&id| id == child).ok_or(SceneError::NotAChild(parent, child))?;
+        siblings.remove(position);
+        Ok(())
+    }
+
+}
+
+impl Default for SceneGraph {
+    fn default() -> Self {
+        SceneGraph::new()
+    }
+}
+
 
-// You can also use square brackets to access the children of a SceneNode
+// You can use square brackets with a handle to access a node in the graph, or with an array of
+// child indices to walk down from the root: scene[[0, 2, 1]] is the old root[0][2][1].
+// Both panic if the node does not exist, use get/get_mut/find_path for the checked variants.
 use std::ops::{Index, IndexMut};
-impl Index<usize> for SceneNode {
+impl Index<NodeId> for SceneGraph {
     type Output = SceneNode;
-    fn index(&self, index: usize) -> &SceneNode {
-        unsafe {
-            & *(self.children[index] as *const SceneNode)
-        }
+    fn index(&self, id: NodeId) -> &SceneNode {
+        self.get(id).unwrap_or_else(|| panic!("{}", SceneError::StaleHandle(id)))
     }
 }
-impl IndexMut<usize> for SceneNode {
-    fn index_mut(&mut self, index: usize) -> &mut SceneNode {
-        unsafe {
-            &mut (*self.children[index])
+impl IndexMut<NodeId> for SceneGraph {
+    fn index_mut(&mut self, id: NodeId) -> &mut SceneNode {
+        self.get_mut(id).unwrap_or_else(|| panic!("{}", SceneError::StaleHandle(id)))
+    }
+}
+impl<const N: usize> Index<[usize; N]> for SceneGraph {
+    type Output = SceneNode;
+    fn index(&self, path: [usize; N]) -> &SceneNode {
+        let id = self.find_path(&path).unwrap_or_else(|| panic!("No node at path {:?}", path));
+        &self[id]
+    }
+}
+impl<const N: usize> IndexMut<[usize; N]> for SceneGraph {
+    fn index_mut(&mut self, path: [usize; N]) -> &mut SceneNode {
+        let id = self.find_path(&path).unwrap_or_else(|| panic!("No node at path {:?}", path));
+        &mut self[id]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rotation;
+
+    // root -> a -> (a1, a2), root -> b -> b1
+    fn small_tree() -> (SceneGraph, [NodeId; 5]) {
+        let mut scene = SceneGraph::new();
+        let root = scene.root();
+        let a  = scene.add_child(root, SceneNode::new()).unwrap();
+        let a1 = scene.add_child(a, SceneNode::new()).unwrap();
+        let a2 = scene.add_child(a, SceneNode::new()).unwrap();
+        let b  = scene.add_child(root, SceneNode::new()).unwrap();
+        let b1 = scene.add_child(b, SceneNode::new()).unwrap();
+        (scene, [a, a1, a2, b, b1])
+    }
+
+    fn assert_translation(matrix: &glm::Mat4, expected: glm::Vec3) {
+        assert!(glm::distance(&matrix.column(3).xyz(), &expected) < 1e-5, "{} != {}", matrix, expected);
+    }
+
+    #[test]
+    fn only_dirty_subtrees_are_updated() {
+        let (mut scene, [a, _, _, b, b1]) = small_tree();
+        assert_eq!(scene.update_transformations(), 6);
+        assert_eq!(scene.update_transformations(), 0);
+
+        scene[b].position.x = 1.0;
+        assert!(scene[b1].is_dirty());
+        assert!(!scene[a].is_dirty());
+        assert_eq!(scene.update_transformations(), 2);
+        assert_translation(&scene[b1].current_transformation_matrix, glm::vec3(1.0, 0.0, 0.0));
+
+        scene[b1].position.y = 1.0;
+        scene[b].position.z = 1.0;
+        assert_eq!(scene.update_transformations(), 2);
+        assert_translation(&scene[b1].current_transformation_matrix, glm::vec3(1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn nodes_can_be_found_by_name_and_tag() {
+        let mut scene = SceneGraph::new();
+        let terrain = scene.add_child(scene.root(), SceneNode::new().with_name("terrain")).unwrap();
+        for i in 0..3 {
+            let helicopter = scene.add_child(terrain, SceneNode::new().with_name(&format!("helicopter_{}", i))).unwrap();
+            scene.add_child(helicopter, SceneNode::new().with_name("main_rotor").with_tag("rotor")).unwrap();
         }
+
+        let rotor = scene.find("helicopter_2/main_rotor").unwrap();
+        assert_eq!(scene.path_of(rotor).unwrap(), "/terrain/helicopter_2/main_rotor");
+        assert_eq!(scene.find("/terrain/helicopter_2/main_rotor"), Some(rotor));
+        assert_eq!(scene.find("/helicopter_2"), None);
+        assert_eq!(scene.find("helicopter_2/tail_rotor"), None);
+        assert_eq!(scene.find_tagged("rotor").last(), Some(&rotor));
+        assert_eq!(scene.find_all(|node| node.name.starts_with("helicopter")).len(), 3);
+    }
+
+    #[test]
+    fn reading_does_not_dirty() {
+        let (mut scene, [a, a1, ..]) = small_tree();
+        scene.update_transformations();
+        let _ = scene[a].position;
+        let _ = scene.get(a1).unwrap().normal_matrix();
+        assert_eq!(scene.update_transformations(), 0);
+    }
+
+    #[test]
+    fn world_matrix_refreshes_only_stale_ancestors() {
+        let (mut scene, [a, a1, a2, b, b1]) = small_tree();
+        scene.update_transformations();
+
+        scene[a].position = glm::vec3(0.0, 2.0, 0.0);
+        scene[a1].position = glm::vec3(3.0, 0.0, 0.0);
+        assert_translation(&scene.world_matrix(a1).unwrap(), glm::vec3(3.0, 2.0, 0.0));
+        assert!(!scene[a].is_dirty());
+        assert!(scene[a2].is_dirty());
+
+        // a2 was left behind, and is still picked up by the next full update
+        assert_eq!(scene.update_transformations(), 1);
+        assert_translation(&scene[a2].current_transformation_matrix, glm::vec3(0.0, 2.0, 0.0));
+        assert_eq!(scene.world_position(b1), Some(glm::zero()));
+        assert!(!scene[b].is_dirty());
+    }
+
+    fn transform(matrix: &glm::Mat4, point: glm::Vec3) -> glm::Vec3 {
+        (matrix * glm::vec4(point.x, point.y, point.z, 1.0)).xyz()
+    }
+
+    fn assert_near(actual: glm::Vec3, expected: glm::Vec3) {
+        assert!(glm::distance(&actual, &expected) < 1e-5, "{} != {}", actual, expected);
+    }
+
+    fn quarter_turn_about_z() -> glm::Quat {
+        rotation::from_axis_angle(&glm::vec3(0.0, 0.0, 1.0), std::f32::consts::FRAC_PI_2)
+    }
+
+    #[test]
+    fn rotation_leaves_the_reference_point_in_place() {
+        let mut node = SceneNode::new();
+        node.reference_point = glm::vec3(1.0, 0.0, 0.0);
+        node.orientation = quarter_turn_about_z();
+        let local = node.local_transformation();
+        assert_near(transform(&local, glm::vec3(1.0, 0.0, 0.0)), glm::vec3(1.0, 0.0, 0.0));
+        assert_near(transform(&local, glm::vec3(2.0, 0.0, 0.0)), glm::vec3(1.0, 1.0, 0.0));
+
+        // The position moves everything, the reference point included
+        node.position = glm::vec3(0.0, 0.0, 5.0);
+        assert_near(transform(&node.local_transformation(), glm::vec3(1.0, 0.0, 0.0)), glm::vec3(1.0, 0.0, 5.0));
+    }
+
+    #[test]
+    fn shear_comes_before_scale_and_rotation() {
+        let mut node = SceneNode::new();
+        node.shear = glm::vec3(2.0, 0.0, 0.0); // x by y
+        assert_near(transform(&node.local_transformation(), glm::vec3(0.0, 1.0, 0.0)), glm::vec3(2.0, 1.0, 0.0));
+
+        // Sheared to (2, 1, 0), scaled to (6, 1, 0) and turned to (-1, 6, 0)
+        node.scale = glm::vec3(3.0, 1.0, 1.0);
+        node.orientation = quarter_turn_about_z();
+        assert_near(transform(&node.local_transformation(), glm::vec3(0.0, 1.0, 0.0)), glm::vec3(-1.0, 6.0, 0.0));
+
+        // Shearing is about the reference point too
+        node.reference_point = glm::vec3(0.0, 1.0, 0.0);
+        assert_near(transform(&node.local_transformation(), glm::vec3(0.0, 1.0, 0.0)), glm::vec3(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn children_are_placed_in_their_parent() {
+        let (mut scene, [a, a1, ..]) = small_tree();
+        scene[a].orientation = quarter_turn_about_z();
+        scene[a].position = glm::vec3(0.0, 0.0, 2.0);
+        scene[a1].position = glm::vec3(1.0, 0.0, 0.0);
+        scene.update_transformations();
+        assert_translation(&scene[a1].current_transformation_matrix, glm::vec3(0.0, 1.0, 2.0));
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_under_non_uniform_scale() {
+        let mut node = SceneNode::new();
+        node.scale = glm::vec3(1.0, 4.0, 1.0);
+        node.orientation = quarter_turn_about_z();
+        node.current_transformation_matrix = node.local_transformation();
+
+        let model = glm::mat4_to_mat3(&node.current_transformation_matrix);
+        let expected = glm::transpose(&glm::inverse(&model));
+        assert!((node.normal_matrix() - expected).abs().max() < 1e-5);
+
+        // The surface of a 45 degree slope, which squashing and stretching tilts
+        let normal = glm::normalize(&glm::vec3(1.0, 1.0, 0.0));
+        let tangent = glm::vec3(1.0, -1.0, 0.0);
+        let transformed_normal = node.normal_matrix() * normal;
+        assert!(glm::dot(&transformed_normal, &(model * tangent)).abs() < 1e-5);
+        assert!(glm::dot(&(model * normal), &(model * tangent)).abs() > 0.1, "mat3(M) should get this wrong");
+    }
+
+    #[test]
+    fn removed_nodes_take_their_subtree_with_them() {
+        let (mut scene, [a, a1, a2, b, b1]) = small_tree();
+        assert_eq!(scene.remove_child(b, a1), Err(SceneError::NotAChild(b, a1)));
+        scene.remove_child(scene.root(), a).unwrap();
+        assert!(!scene.contains(a) && !scene.contains(a1) && !scene.contains(a2));
+        scene.remove(b1).unwrap();
+        assert!(scene[b].children().is_empty());
+        assert_eq!(scene.remove(b1), Err(SceneError::StaleHandle(b1)));
+    }
+
+    #[test]
+    fn handles_to_a_reused_slot_are_stale() {
+        let (mut scene, [_, a1, _, b, _]) = small_tree();
+        scene.remove(a1).unwrap();
+        let c = scene.add_child(b, SceneNode::new().with_name("c")).unwrap();
+        assert_eq!(c.index, a1.index);
+        assert_ne!(c.generation, a1.generation);
+
+        assert!(scene.get(a1).is_none());
+        assert_eq!(scene[c].name, "c");
+        assert_eq!(scene.add_child(a1, SceneNode::new()), Err(SceneError::StaleHandle(a1)));
+        assert_eq!(scene.reparent(c, a1), Err(SceneError::StaleHandle(a1)));
+        assert_eq!(scene.remove(a1), Err(SceneError::StaleHandle(a1)));
+    }
+
+    #[test]
+    fn nodes_can_not_be_moved_below_themselves() {
+        let (mut scene, [a, a1, ..]) = small_tree();
+        assert_eq!(scene.reparent(a, a1), Err(SceneError::WouldCreateCycle(a)));
+        assert_eq!(scene.reparent(a, a), Err(SceneError::WouldCreateCycle(a)));
+        assert_eq!(scene[a1].parent, Some(a));
+    }
+
+    #[test]
+    fn the_root_stays_where_it_is() {
+        let (mut scene, [a, ..]) = small_tree();
+        let root = scene.root();
+        assert_eq!(scene.remove(root), Err(SceneError::CannotMoveRoot));
+        assert_eq!(scene.reparent(root, a), Err(SceneError::CannotMoveRoot));
+        assert!(scene.contains(root));
+    }
+
+    #[test]
+    fn reparenting_dirties_the_moved_subtree() {
+        let (mut scene, [a, _, _, b, b1]) = small_tree();
+        scene[a].position = glm::vec3(5.0, 0.0, 0.0);
+        scene.update_transformations();
+
+        scene.reparent(b, a).unwrap();
+        assert_eq!(scene.update_transformations(), 2);
+        assert_translation(&scene[b1].current_transformation_matrix, glm::vec3(5.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn dirty_nodes_moved_under_a_clean_parent_are_updated() {
+        let mut scene = SceneGraph::new();
+        let root = scene.root();
+        let a  = scene.add_child(root, SceneNode::new()).unwrap();
+        let a1 = scene.add_child(a, SceneNode::new()).unwrap();
+        let mut b = SceneNode::new();
+        b.position = glm::vec3(0.0, 2.0, 0.0);
+        let b  = scene.add_child(root, b).unwrap();
+        scene.update_transformations();
+
+        // a1 is already dirty when it moves, and a no longer reaches it
+        scene[a].position = glm::vec3(3.0, 0.0, 0.0);
+        scene.reparent(a1, b).unwrap();
+        scene.update_transformations();
+        assert!(!scene[a1].is_dirty());
+        assert_translation(&scene[a1].current_transformation_matrix, glm::vec3(0.0, 2.0, 0.0));
     }
 }
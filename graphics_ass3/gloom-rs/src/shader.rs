@@ -1,154 +1,1007 @@
-use gl;
+extern crate nalgebra_glm as glm;
+
 use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io,
     ptr,
     str,
     ffi::CString,
-    path::Path,
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
 };
 
+use crate::debug_output;
+use crate::program_cache::{CachedSource, ProgramCache};
+use crate::shader_log::{self, SourceFile};
+use crate::shader_reflection::{self, Block, GlslType, UniformError, Variable};
+use crate::uniform_buffer::{Std140, UniformBuffer};
+
 pub struct Shader {
     pub program_id: u32,
+    uniforms: HashMap<String, Variable>,
+    attributes: HashMap<String, Variable>,
+    blocks: HashMap<String, Block>,
+    storage_blocks: HashMap<String, Block>,
+    block_bindings: Vec<(String, u32, usize)>,   // Kept to bind them again after a reload
+    storage_bindings: Vec<(String, u32)>,
+    work_group_size: Option<[u32; 3]>,           // Only for compute programs
+    stages: Vec<Stage>,
+    files: Vec<WatchedFile>,
+    reloadable: bool,                // False if any stage was compiled from a string
+    cache: Option<ProgramCache>,     // Used again for reloads
+    watching: bool,
+    last_poll: Option<Instant>,
 }
 
 pub struct ShaderBuilder {
     program_id: u32,
+    sources: Vec<ShaderSource>,      // Compiled when the program is linked
     shaders: Vec::<u32>,
+    stages: Vec<Stage>,
+    files: Vec<WatchedFile>,
+    reloadable: bool,
+    preprocessor: Preprocessor,      // The defines for the files attached from now on
+    cache: Option<ProgramCache>,
+}
+
+// One attached shader, ready for the driver
+struct ShaderSource {
+    name   : String,                 // Where it came from, for error messages
+    kind   : ShaderType,
+    source : String,
+    files  : Vec<SourceFile>,        // What the source string numbers in it refer to
+}
+
+// How one stage of a program was made, so that it can be made again when its file changes
+#[derive(Clone, Debug)]
+struct Stage {
+    path         : String,
+    preprocessor : Preprocessor,
+}
+
+// A file the program was built from, and when it was last changed at the time
+#[derive(Clone, Debug)]
+struct WatchedFile {
+    path     : String,
+    modified : Option<SystemTime>,
+}
+
+// How often a watched shader looks at its files. Checking every frame is a waste of system calls
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
 }
 
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ShaderType {
     Vertex,
     Fragment,
     TessellationControl,
     TessellationEvaluation,
     Geometry,
+    Compute,
+}
+
+// Everything that can go wrong while building a shader program. The logs are the driver's own
+// info logs, which say what is wrong and where. Compile errors keep the files as they were before
+// preprocessing, so the lines the log points at can be shown next to it.
+#[derive(Debug)]
+pub enum ShaderError {
+    Io { path: String, error: io::Error },
+    UnknownStage { path: String },   // The file extension is not one of the known shader stages
+    Include { path: String, line: usize, included: String, error: io::Error },
+    IncludeCycle { chain: Vec<String> },
+    Preprocess { path: String, line: usize, message: String },
+    Compile { path: String, stage: ShaderType, log: String, files: Vec<SourceFile> },
+    Link { paths: Vec<String>, log: String },
+    Uniform(UniformError),           // A reloaded program no longer fits what it is bound to
+    NotCompute { program: String },  // Only compute programs can be dispatched
 }
 
+impl fmt::Display for ShaderType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ShaderType::Vertex                  => "vertex",
+            ShaderType::Fragment                => "fragment",
+            ShaderType::TessellationControl     => "tessellation control",
+            ShaderType::TessellationEvaluation  => "tessellation evaluation",
+            ShaderType::Geometry                => "geometry",
+            ShaderType::Compute                 => "compute",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShaderError::Io { path, error } =>
+                write!(f, "Failed to read shader source {}: {}", path, error),
+            ShaderError::UnknownStage { path } =>
+                write!(f, "Can not tell the shader stage of {} from its extension, use .vert, .frag, .tcs, .tes, .geom or .comp", path),
+            ShaderError::Include { path, line, included, error } =>
+                write!(f, "{}:{}: Failed to include {}: {}", path, line, included, error),
+            ShaderError::IncludeCycle { chain } =>
+                write!(f, "Shader files include each other in a cycle: {}", chain.join(" -> ")),
+            ShaderError::Preprocess { path, line, message } =>
+                write!(f, "{}:{}: {}", path, line, message),
+            ShaderError::Compile { path, stage, log, files } =>
+                write!(f, "Failed to compile {} as a {} shader:\n{}", path, stage, shader_log::annotate(files, log)),
+            ShaderError::Link { paths, log } =>
+                write!(f, "Failed to link {}:\n{}", paths.join(", "), log),
+            ShaderError::Uniform(e) =>
+                write!(f, "{}", e),
+            ShaderError::NotCompute { program } =>
+                write!(f, "Can not dispatch {}, it is not a compute program", program),
+        }
+    }
+}
+
+impl std::error::Error for ShaderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ShaderError::Io { error, .. } => Some(error),
+            ShaderError::Include { error, .. } => Some(error),
+            ShaderError::Uniform(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
 impl Shader {
-    // Make sure the shader is active before calling this
-    pub unsafe fn get_uniform_location(&self, name: &str) -> i32 {
-        let name_cstr = CString::new(name).expect("CString::new failed");
-        gl::GetUniformLocation(self.program_id, name_cstr.as_ptr())
+    // -1 if there is no such active uniform, like glGetUniformLocation
+    pub fn get_uniform_location(&self, name: &str) -> i32 {
+        self.uniforms.get(name).map_or(-1, |uniform| uniform.location)
+    }
+
+    // The uniforms and attributes the program uses, found when it was linked
+    pub fn uniforms(&self) -> &HashMap<String, Variable> {
+        &self.uniforms
+    }
+
+    pub fn attributes(&self) -> &HashMap<String, Variable> {
+        &self.attributes
+    }
+
+    // Looks up a uniform and checks that it has the type it is about to be set as
+    fn uniform(&self, name: &str, expected: &[GlslType]) -> Result<i32, UniformError> {
+        // Setters are called for every node every frame, so the name of the program is only put
+        // together once something is wrong
+        let uniform = self.uniforms.get(name).ok_or_else(|| UniformError::Unknown {
+            name: name.to_string(),
+            suggestion: shader_reflection::suggest(name, self.uniforms.keys()),
+            program: self.program_name(),
+        })?;
+        if !expected.contains(&uniform.kind) {
+            return Err(UniformError::WrongType { name: name.to_string(), program: self.program_name(), expected: expected[0], found: uniform.kind });
+        }
+        if uniform.location < 0 {
+            return Err(UniformError::InBlock { name: name.to_string(), program: self.program_name() });
+        }
+        Ok(uniform.location)
+    }
+
+    // Makes the uniform block read from the buffer. Every program which binds a block to the same
+    // buffer shares it, so it only has to be filled once per frame. The binding is kept when the
+    // program is reloaded.
+    pub unsafe fn bind_uniform_buffer<T: Std140>(&mut self, block: &str, buffer: &UniformBuffer<T>) -> Result<(), UniformError> {
+        self.bind_uniform_block(block, buffer.binding(), buffer.size())?;
+        self.block_bindings.retain(|(name, _, _)| name != block);
+        self.block_bindings.push((block.to_string(), buffer.binding(), buffer.size()));
+        Ok(())
+    }
+
+    unsafe fn bind_uniform_block(&self, name: &str, binding: u32, size: usize) -> Result<(), UniformError> {
+        let block = self.blocks.get(name).ok_or_else(|| UniformError::UnknownBlock {
+            name: name.to_string(),
+            suggestion: shader_reflection::suggest(name, self.blocks.keys()),
+            program: self.program_name(),
+        })?;
+        // Drivers may pad the end of a block, but never by more than a vec4
+        if block.size < size || block.size.div_ceil(16) != size.div_ceil(16) {
+            return Err(UniformError::BlockSize { name: name.to_string(), program: self.program_name(), expected: size, found: block.size });
+        }
+        gl::UniformBlockBinding(self.program_id, block.index, binding);
+        Ok(())
+    }
+
+    // Makes the shader storage block read from and write to whatever buffer is bound to the
+    // binding point, see StorageBuffer::bind. The binding is kept when the program is reloaded.
+    pub unsafe fn bind_storage_block(&mut self, block: &str, binding: u32) -> Result<(), UniformError> {
+        self.apply_storage_binding(block, binding)?;
+        self.storage_bindings.retain(|(name, _)| name != block);
+        self.storage_bindings.push((block.to_string(), binding));
+        Ok(())
+    }
+
+    unsafe fn apply_storage_binding(&self, name: &str, binding: u32) -> Result<(), UniformError> {
+        let block = self.storage_blocks.get(name).ok_or_else(|| UniformError::UnknownBlock {
+            name: name.to_string(),
+            suggestion: shader_reflection::suggest(name, self.storage_blocks.keys()),
+            program: self.program_name(),
+        })?;
+        gl::ShaderStorageBlockBinding(self.program_id, block.index, binding);
+        Ok(())
+    }
+
+    // Runs a compute program with the given number of work groups in each direction. Follow it
+    // with memory_barrier before using what it wrote.
+    pub unsafe fn dispatch(&self, groups: [u32; 3]) -> Result<(), ShaderError> {
+        if self.work_group_size.is_none() {
+            return Err(ShaderError::NotCompute { program: self.program_name() });
+        }
+        self.activate();
+        gl::DispatchCompute(groups[0], groups[1], groups[2]);
+        Ok(())
+    }
+
+    // Runs a compute program once for each of the items, with enough work groups to cover them.
+    // The shader has to skip the invocations past the end when the counts do not divide evenly.
+    pub unsafe fn dispatch_items(&self, items: [u32; 3]) -> Result<(), ShaderError> {
+        let size = self.work_group_size.ok_or_else(|| ShaderError::NotCompute { program: self.program_name() })?;
+        self.dispatch(work_groups_for(items, size))
+    }
+
+    // The setters below work whether or not the program is active
+    pub unsafe fn set_mat4(&self, name: &str, value: &glm::Mat4) -> Result<(), UniformError> {
+        let location = self.uniform(name, &[GlslType::Mat4])?;
+        gl::ProgramUniformMatrix4fv(self.program_id, location, 1, gl::FALSE, value.as_ptr());
+        Ok(())
+    }
+
+    pub unsafe fn set_mat3(&self, name: &str, value: &glm::Mat3) -> Result<(), UniformError> {
+        let location = self.uniform(name, &[GlslType::Mat3])?;
+        gl::ProgramUniformMatrix3fv(self.program_id, location, 1, gl::FALSE, value.as_ptr());
+        Ok(())
+    }
+
+    pub unsafe fn set_vec4(&self, name: &str, value: &glm::Vec4) -> Result<(), UniformError> {
+        let location = self.uniform(name, &[GlslType::Vec4])?;
+        gl::ProgramUniform4fv(self.program_id, location, 1, value.as_ptr());
+        Ok(())
+    }
+
+    pub unsafe fn set_vec3(&self, name: &str, value: &glm::Vec3) -> Result<(), UniformError> {
+        let location = self.uniform(name, &[GlslType::Vec3])?;
+        gl::ProgramUniform3fv(self.program_id, location, 1, value.as_ptr());
+        Ok(())
+    }
+
+    pub unsafe fn set_vec2(&self, name: &str, value: &glm::Vec2) -> Result<(), UniformError> {
+        let location = self.uniform(name, &[GlslType::Vec2])?;
+        gl::ProgramUniform2fv(self.program_id, location, 1, value.as_ptr());
+        Ok(())
+    }
+
+    pub unsafe fn set_f32(&self, name: &str, value: f32) -> Result<(), UniformError> {
+        let location = self.uniform(name, &[GlslType::Float])?;
+        gl::ProgramUniform1f(self.program_id, location, value);
+        Ok(())
+    }
+
+    // Samplers are set as ints too, to the texture unit they should read from
+    pub unsafe fn set_i32(&self, name: &str, value: i32) -> Result<(), UniformError> {
+        let location = self.uniform(name, &[GlslType::Int, GlslType::Sampler2D])?;
+        gl::ProgramUniform1i(self.program_id, location, value);
+        Ok(())
+    }
+
+    pub unsafe fn set_u32(&self, name: &str, value: u32) -> Result<(), UniformError> {
+        let location = self.uniform(name, &[GlslType::UInt])?;
+        gl::ProgramUniform1ui(self.program_id, location, value);
+        Ok(())
+    }
+
+    pub unsafe fn set_bool(&self, name: &str, value: bool) -> Result<(), UniformError> {
+        let location = self.uniform(name, &[GlslType::Bool])?;
+        gl::ProgramUniform1i(self.program_id, location, value as i32);
+        Ok(())
     }
 
     pub unsafe fn activate(&self) {
         gl::UseProgram(self.program_id);
     }
+
+    // The files the program was built from
+    pub fn paths(&self) -> Vec<&str> {
+        self.stages.iter().map(|stage| stage.path.as_str()).collect()
+    }
+
+    // How the program is called in errors, like "simple.vert + simple.frag"
+    fn program_name(&self) -> String {
+        self.paths().join(" + ")
+    }
+
+    // In watch mode, reload_if_changed rebuilds the program whenever one of its files is saved
+    pub fn watch(&mut self, enabled: bool) {
+        self.watching = enabled && self.reloadable;
+    }
+
+    // Call this once per frame. Returns Ok(true) if the files had changed and the program was
+    // rebuilt. If the new version fails to build, the current program is kept and the error is
+    // returned; the files are not tried again until they change once more.
+    pub unsafe fn reload_if_changed(&mut self) -> Result<bool, ShaderError> {
+        if !self.watching || self.last_poll.is_some_and(|last| last.elapsed() < POLL_INTERVAL) {
+            return Ok(false);
+        }
+        self.last_poll = Some(Instant::now());
+
+        let mut changed = false;
+        for file in &mut self.files {
+            let now = modified(&file.path);
+            if now != file.modified {
+                file.modified = now;
+                changed = true;
+            }
+        }
+        if !changed {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    // Builds the program again from its files, and swaps it in if that works. The program id
+    // changes, and uniforms have to be set again.
+    pub unsafe fn reload(&mut self) -> Result<(), ShaderError> {
+        let mut builder = ShaderBuilder::new();
+        builder.cache = self.cache.clone();
+        for stage in &self.stages {
+            builder = builder.attach_stage(stage.clone())?;
+        }
+        let mut new = builder.link()?;
+        for (name, binding, size) in &self.block_bindings {
+            new.bind_uniform_block(name, *binding, *size).map_err(ShaderError::Uniform)?;
+        }
+        for (name, binding) in &self.storage_bindings {
+            new.apply_storage_binding(name, *binding).map_err(ShaderError::Uniform)?;
+        }
+        // The old program ends up in `new`, and is deleted when it is dropped
+        std::mem::swap(&mut self.program_id, &mut new.program_id);
+        self.files = std::mem::take(&mut new.files);
+        self.uniforms = std::mem::take(&mut new.uniforms);
+        self.attributes = std::mem::take(&mut new.attributes);
+        self.blocks = std::mem::take(&mut new.blocks);
+        self.storage_blocks = std::mem::take(&mut new.storage_blocks);
+        self.work_group_size = new.work_group_size;
+        Ok(())
+    }
 }
 
-impl Into<gl::types::GLenum> for ShaderType {
-    fn into(self) -> gl::types::GLenum {
-        match self {
+impl Drop for Shader {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteProgram(self.program_id) };
+    }
+}
+
+impl From<ShaderType> for gl::types::GLenum {
+    fn from(shader_type: ShaderType) -> Self {
+        match shader_type {
             ShaderType::Vertex                  => { gl::VERTEX_SHADER          },
             ShaderType::Fragment                => { gl::FRAGMENT_SHADER        },
             ShaderType::TessellationControl     => { gl::TESS_CONTROL_SHADER    },
             ShaderType::TessellationEvaluation  => { gl::TESS_EVALUATION_SHADER } ,
             ShaderType::Geometry                => { gl::GEOMETRY_SHADER        },
+            ShaderType::Compute                 => { gl::COMPUTE_SHADER         },
         }
     }
 }
 
 impl ShaderType {
-    fn from_ext(ext: &std::ffi::OsStr) -> Result<ShaderType, String> {
-        match ext.to_str().expect("Failed to read extension") {
-            "vert" => { Ok(ShaderType::Vertex) },
-            "frag" => { Ok(ShaderType::Fragment) },
-            "tcs"  => { Ok(ShaderType::TessellationControl) },
-            "tes"  => { Ok(ShaderType::TessellationEvaluation) },
-            "geom" => { Ok(ShaderType::Geometry) },
-            e => { Err(e.to_string()) },
+    pub fn from_ext(ext: &std::ffi::OsStr) -> Option<ShaderType> {
+        match ext.to_str()? {
+            "vert" => { Some(ShaderType::Vertex) },
+            "frag" => { Some(ShaderType::Fragment) },
+            "tcs"  => { Some(ShaderType::TessellationControl) },
+            "tes"  => { Some(ShaderType::TessellationEvaluation) },
+            "geom" => { Some(ShaderType::Geometry) },
+            "comp" => { Some(ShaderType::Compute) },
+            _ => { None },
         }
     }
+
+    // The stage of a shader file, going by its extension
+    pub fn from_path(shader_path: &str) -> Result<ShaderType, ShaderError> {
+        Path::new(shader_path).extension()
+            .and_then(ShaderType::from_ext)
+            .ok_or_else(|| ShaderError::UnknownStage { path: shader_path.to_string() })
+    }
 }
 
+// All the builder methods take the builder by value and give it back on success, so that they can
+// be chained with `?`. If anything fails, the builder is dropped, which deletes what it has created.
+// The shaders are not compiled until the program is linked, so that a cached binary of the whole
+// program can be used instead, which means that compile errors come from link.
+#[cfg_attr(not(test), allow(dead_code))]
 impl ShaderBuilder {
     pub unsafe fn new() -> ShaderBuilder {
         ShaderBuilder {
             program_id: gl::CreateProgram(),
+            sources: vec![],
             shaders: vec![],
+            stages: vec![],
+            files: vec![],
+            reloadable: true,
+            preprocessor: Preprocessor::new(),
+            cache: None,
         }
     }
 
-    pub unsafe fn attach_file(self, shader_path: &str) -> ShaderBuilder {
-        let path = Path::new(shader_path);
-        if let Some(extension) = path.extension() {
-            let shader_type = ShaderType::from_ext(extension)
-                .expect("Failed to parse file extension.");
-            let shader_src = std::fs::read_to_string(path)
-                .expect(&format!("Failed to read shader source. {}", shader_path));
-            self.compile_shader(&shader_src, shader_type)
-        } else {
-            panic!("Failed to read extension of file with path: {}", shader_path);
-        }
+    // Looks for the linked program in the cache before compiling anything, and puts it there
+    // after linking it otherwise
+    pub fn cache(mut self, cache: &ProgramCache) -> ShaderBuilder {
+        self.cache = Some(cache.clone());
+        self
     }
 
-    pub unsafe fn compile_shader(mut self, shader_src: &str, shader_type: ShaderType) -> ShaderBuilder {
-        let shader = gl::CreateShader(shader_type.into());
-        let c_str_shader = CString::new(shader_src.as_bytes()).unwrap();
+    // Defines a macro for every file attached after this, to compile a permutation of a shader
+    // without editing it. For instance `.define("NORMAL_MAPPING", "1")`.
+    pub fn define(mut self, name: &str, value: &str) -> ShaderBuilder {
+        self.preprocessor.define(name, value);
+        self
+    }
+
+    pub unsafe fn attach_file(self, shader_path: &str) -> Result<ShaderBuilder, ShaderError> {
+        let preprocessor = self.preprocessor.clone();
+        self.attach_stage(Stage { path: shader_path.to_string(), preprocessor })
+    }
+
+    // Like attach_file, but with some extra source code inserted right after the #version line,
+    // such as declarations generated on the Rust side
+    pub unsafe fn attach_file_with_prelude(self, shader_path: &str, prelude: &str) -> Result<ShaderBuilder, ShaderError> {
+        let mut preprocessor = self.preprocessor.clone();
+        preprocessor.prelude(prelude);
+        self.attach_stage(Stage { path: shader_path.to_string(), preprocessor })
+    }
+
+    unsafe fn attach_stage(mut self, stage: Stage) -> Result<ShaderBuilder, ShaderError> {
+        let shader_type = ShaderType::from_path(&stage.path)?;
+        let files = &mut self.files;
+        let processed = stage.preprocessor.process(&stage.path, |path| {
+            // Look at the time before reading, so that a save in between is not missed
+            files.push(WatchedFile { path: path.to_string(), modified: modified(path) });
+            std::fs::read_to_string(path)
+        })?;
+        self.sources.push(ShaderSource { name: stage.path.clone(), kind: shader_type, source: processed.source, files: processed.files });
+        self.stages.push(stage);
+        Ok(self)
+    }
+
+    // Shaders compiled from a string can not be reloaded, since there is no file to watch
+    pub unsafe fn compile_shader(mut self, shader_src: &str, shader_type: ShaderType) -> Result<ShaderBuilder, ShaderError> {
+        self.reloadable = false;
+        let files = vec![SourceFile { path: "<source>".to_string(), text: shader_src.to_string() }];
+        self.sources.push(ShaderSource { name: "<source>".to_string(), kind: shader_type, source: shader_src.to_string(), files });
+        Ok(self)
+    }
+
+    unsafe fn compile(&mut self, source: &ShaderSource) -> Result<(), ShaderError> {
+        let shader = gl::CreateShader(source.kind.into());
+        // Interior nul bytes can not be passed to OpenGL, so let the compiler complain about them
+        let c_str_shader = CString::new(source.source.replace('\0', " ")).unwrap();
         gl::ShaderSource(shader, 1, &c_str_shader.as_ptr(), ptr::null());
         gl::CompileShader(shader);
 
-        if !self.check_shader_errors(shader) {
-            panic!("Shader failed to compile.");
+        if let Err(log) = self.check_shader_errors(shader) {
+            gl::DeleteShader(shader);
+            return Err(ShaderError::Compile { path: source.name.clone(), stage: source.kind, log, files: source.files.clone() });
         }
 
         self.shaders.push(shader);
+        Ok(())
+    }
 
-        self
+    fn paths(&self) -> Vec<String> {
+        self.sources.iter().map(|source| source.name.clone()).collect()
     }
 
-    unsafe fn check_shader_errors(&self, shader_id: u32) -> bool {
+    unsafe fn check_shader_errors(&self, shader_id: u32) -> Result<(), String> {
         let mut success = i32::from(gl::FALSE);
-        let mut info_log = Vec::with_capacity(512);
-        info_log.set_len(512 - 1);
         gl::GetShaderiv(shader_id, gl::COMPILE_STATUS, &mut success);
         if success != i32::from(gl::TRUE) {
-            gl::GetShaderInfoLog(
-                shader_id,
-                512,
-                ptr::null_mut(),
-                info_log.as_mut_ptr() as *mut gl::types::GLchar,
-            );
-            println!("ERROR::Shader Compilation Failed!\n{}", String::from_utf8_lossy(&info_log));
-            return false;
+            let mut length = 0;
+            gl::GetShaderiv(shader_id, gl::INFO_LOG_LENGTH, &mut length);
+            return Err(read_info_log(length, |capacity, written, buffer| {
+                gl::GetShaderInfoLog(shader_id, capacity, written, buffer)
+            }));
         }
-        true
+        Ok(())
     }
 
-    unsafe fn check_linker_errors(&self) -> bool {
+    unsafe fn check_linker_errors(&self) -> Result<(), String> {
         let mut success = i32::from(gl::FALSE);
-        let mut info_log = Vec::with_capacity(512);
-        info_log.set_len(512 - 1);
         gl::GetProgramiv(self.program_id, gl::LINK_STATUS, &mut success);
         if success != i32::from(gl::TRUE) {
-            gl::GetProgramInfoLog(
-                self.program_id,
-                512,
-                ptr::null_mut(),
-                info_log.as_mut_ptr() as *mut gl::types::GLchar,
-            );
-            println!("ERROR::SHADER::PROGRAM::COMPILATION_FAILED\n{}", String::from_utf8_lossy(&info_log));
-            return false;
+            let mut length = 0;
+            gl::GetProgramiv(self.program_id, gl::INFO_LOG_LENGTH, &mut length);
+            return Err(read_info_log(length, |capacity, written, buffer| {
+                gl::GetProgramInfoLog(self.program_id, capacity, written, buffer)
+            }));
         }
-        true
+        Ok(())
     }
 
     #[must_use = "The shader program is useless if not stored in a variable."]
-    pub unsafe fn link(self) -> Shader {
-        for &shader in &self.shaders {
-            gl::AttachShader(self.program_id, shader);
+    pub unsafe fn link(mut self) -> Result<Shader, ShaderError> {
+        let compute = self.sources.iter().any(|source| source.kind == ShaderType::Compute);
+        if compute && self.sources.len() > 1 {
+            let log = "A compute shader has to be the only shader in its program".to_string();
+            return Err(ShaderError::Link { paths: self.paths(), log });
         }
-        gl::LinkProgram(self.program_id);
 
-        // todo:: use this to make safer abstraction
-        self.check_linker_errors();
+        let key = self.cache.as_ref().map(|cache| {
+            let sources: Vec<_> = self.sources.iter()
+                .map(|source| CachedSource { stage: source.kind, source: &source.source })
+                .collect();
+            cache.key(&sources)
+        });
+        let cached = match (&self.cache, key) {
+            (Some(cache), Some(key)) => cache.load(key, self.program_id),
+            _ => false,
+        };
 
-        for &shader in &self.shaders {
-            gl::DeleteShader(shader);
+        // Without a cached binary, or with one the driver did not take, build it from source
+        if !cached {
+            let sources = std::mem::take(&mut self.sources);
+            for source in &sources {
+                self.compile(source)?;
+            }
+            self.sources = sources;
+
+            for &shader in &self.shaders {
+                gl::AttachShader(self.program_id, shader);
+            }
+            if self.cache.is_some() {
+                gl::ProgramParameteri(self.program_id, gl::PROGRAM_BINARY_RETRIEVABLE_HINT, gl::TRUE as i32);
+            }
+            gl::LinkProgram(self.program_id);
+
+            self.check_linker_errors()
+                .map_err(|log| ShaderError::Link { paths: self.paths(), log })?;
+
+            if let (Some(cache), Some(key)) = (&self.cache, key) {
+                cache.store(key, self.program_id);
+            }
+        }
+
+        debug_output::label(debug_output::Object::Program, self.program_id, &self.paths().join(" + "));
+
+        // The program is handed over, so dropping the builder only deletes the shader objects
+        let program_id = std::mem::replace(&mut self.program_id, 0);
+        Ok(Shader {
+            program_id,
+            uniforms: shader_reflection::active_uniforms(program_id),
+            attributes: shader_reflection::active_attributes(program_id),
+            blocks: shader_reflection::active_uniform_blocks(program_id),
+            storage_blocks: shader_reflection::active_storage_blocks(program_id),
+            block_bindings: vec![],
+            storage_bindings: vec![],
+            work_group_size: if compute { Some(work_group_size(program_id)) } else { None },
+            stages: std::mem::take(&mut self.stages),
+            files: std::mem::take(&mut self.files),
+            reloadable: self.reloadable,
+            cache: self.cache.take(),
+            watching: false,
+            last_poll: None,
+        })
+    }
+}
+
+impl Drop for ShaderBuilder {
+    fn drop(&mut self) {
+        unsafe {
+            for &shader in &self.shaders {
+                gl::DeleteShader(shader);
+            }
+            if self.program_id != 0 {
+                gl::DeleteProgram(self.program_id);
+            }
+        }
+    }
+}
+
+unsafe fn work_group_size(program_id: u32) -> [u32; 3] {
+    let mut size = [0i32; 3];
+    gl::GetProgramiv(program_id, gl::COMPUTE_WORK_GROUP_SIZE, size.as_mut_ptr());
+    size.map(|n| n.max(1) as u32)
+}
+
+// How many work groups of `size` it takes to cover `items` in each direction
+#[cfg_attr(not(test), allow(dead_code))]
+fn work_groups_for(items: [u32; 3], size: [u32; 3]) -> [u32; 3] {
+    [0, 1, 2].map(|i| items[i].div_ceil(size[i].max(1)))
+}
+
+// Reads a whole info log, given its length including the terminating nul as reported by
+// INFO_LOG_LENGTH and the function which reads it
+unsafe fn read_info_log(length: i32, read: impl FnOnce(i32, *mut i32, *mut gl::types::GLchar)) -> String {
+    let mut info_log = vec![0u8; length.max(1) as usize];
+    let mut written = 0;
+    read(info_log.len() as i32, &mut written, info_log.as_mut_ptr() as *mut gl::types::GLchar);
+    info_log.truncate(written.max(0) as usize);
+    String::from_utf8_lossy(&info_log).into_owned()
+}
+
+
+// == // GLSL preprocessing // == //
+//
+// Runs before the driver sees the source, and handles what the GLSL preprocessor can not:
+//
+//   * `#include "file"` pastes in another file, found relative to the file including it.
+//     A file with `#pragma once` is only included the first time, and including a file which is
+//     already being included is an error instead of a stack overflow.
+//   * Macros defined from Rust, and an optional prelude of generated code, go right after the
+//     `#version` line, which has to come first.
+//
+// Every file gets its own source string number, and #line directives are put in wherever the
+// source switches between files, so the line numbers in the driver's messages point into the
+// right file. The files are listed in PreprocessedSource::files, indexed by that number.
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Preprocessor {
+    defines : Vec<(String, String)>,
+    prelude : String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreprocessedSource {
+    pub source : String,
+    pub files  : Vec<SourceFile>,
+}
+
+// The name the generated code at the top goes by in error messages
+const GENERATED: &str = "<generated>";
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl Preprocessor {
+    pub fn new() -> Preprocessor {
+        Preprocessor::default()
+    }
+
+    // Defining the same name twice replaces the value
+    pub fn define(&mut self, name: &str, value: &str) {
+        match self.defines.iter_mut().find(|(n, _)| n == name) {
+            Some(define) => define.1 = value.to_string(),
+            None         => self.defines.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn prelude(&mut self, code: &str) {
+        self.prelude += code;
+        if !self.prelude.ends_with('\n') {
+            self.prelude.push('\n');
+        }
+    }
+
+    // The code which goes after the #version line
+    fn header(&self) -> String {
+        let mut header = String::new();
+        for (name, value) in &self.defines {
+            header += &format!("#define {} {}\n", name, value).replace(" \n", "\n");
+        }
+        header + &self.prelude
+    }
+
+    // Preprocesses the file at `path`. Files are read with `read`, which makes it possible to
+    // preprocess without touching the file system.
+    pub fn process(&self, path: &str, read: impl FnMut(&str) -> io::Result<String>) -> Result<PreprocessedSource, ShaderError> {
+        let path = normalize(Path::new(path));
+        let mut expansion = Expansion { read, files: vec![], stack: vec![], once: HashSet::new(), source: String::new() };
+        let text = (expansion.read)(&path).map_err(|error| ShaderError::Io { path: path.clone(), error })?;
+        let has_version = text.lines().any(|line| line.trim_start().starts_with("#version"));
+        expansion.files.push(SourceFile { path, text });
+
+        let header = self.header();
+        let mut header_index = None;
+        if !header.is_empty() {
+            header_index = Some(expansion.files.len());
+            expansion.files.push(SourceFile { path: GENERATED.to_string(), text: header.clone() });
+        }
+        let write_header = |source: &mut String, next_line: usize| {
+            if let Some(index) = header_index {
+                *source += &format!("#line 1 {}\n{}#line {} 0\n", index, header, next_line);
+            }
+        };
+        if !has_version {
+            write_header(&mut expansion.source, 1);
         }
+        expansion.expand(0, write_header)?;
+        Ok(PreprocessedSource { source: expansion.source, files: expansion.files })
+    }
+}
+
+struct Expansion<R> {
+    read   : R,
+    files  : Vec<SourceFile>,
+    stack  : Vec<usize>,             // The files being included, outermost first
+    once   : HashSet<usize>,         // The files with #pragma once
+    source : String,
+}
+
+impl<R: FnMut(&str) -> io::Result<String>> Expansion<R> {
+    fn expand(&mut self, index: usize, mut after_version: impl FnMut(&mut String, usize)) -> Result<(), ShaderError> {
+        self.stack.push(index);
+        let text = self.files[index].text.clone();
+        let mut seen_version = false;
+        for (i, line) in text.lines().enumerate() {
+            let number = i + 1;
+            let directive = line.trim_start();
+            if let Some(rest) = directive.strip_prefix("#include") {
+                let name = include_name(rest).ok_or_else(|| ShaderError::Preprocess {
+                    path: self.files[index].path.clone(), line: number,
+                    message: format!("Expected #include \"file\", found {}", directive),
+                })?;
+                self.include(index, number, name)?;
+                self.source += &format!("#line {} {}\n", number + 1, index);
+            } else if directive.starts_with("#pragma") && directive.split_whitespace().nth(1) == Some("once") {
+                self.once.insert(index);
+                self.source.push('\n');
+            } else if directive.starts_with("#version") {
+                // Only the main file decides the version, included files may have one to be
+                // valid on their own
+                if index == 0 && !seen_version {
+                    seen_version = true;
+                    self.source += line;
+                    self.source.push('\n');
+                    after_version(&mut self.source, number + 1);
+                } else {
+                    self.source.push('\n');
+                }
+            } else {
+                self.source += line;
+                self.source.push('\n');
+            }
+        }
+        self.stack.pop();
+        Ok(())
+    }
+
+    fn include(&mut self, from: usize, line: usize, name: &str) -> Result<(), ShaderError> {
+        let including = &self.files[from].path;
+        let path = normalize(&Path::new(including).parent().unwrap_or(Path::new("")).join(name));
+        let index = match self.files.iter().position(|file| file.path == path) {
+            Some(index) => index,
+            None => {
+                let text = (self.read)(&path).map_err(|error| ShaderError::Include {
+                    path: including.clone(), line, included: path.clone(), error,
+                })?;
+                self.files.push(SourceFile { path, text });
+                self.files.len() - 1
+            },
+        };
+        if self.once.contains(&index) {
+            return Ok(());
+        }
+        if self.stack.contains(&index) {
+            let start = self.stack.iter().position(|&i| i == index).unwrap();
+            let chain = self.stack[start..].iter().chain(Some(&index))
+                .map(|&i| self.files[i].path.clone())
+                .collect();
+            return Err(ShaderError::IncludeCycle { chain });
+        }
+        self.source += &format!("#line 1 {}\n", index);
+        self.expand(index, |_, _| {})
+    }
+}
+
+// `"file"` or `<file>`
+fn include_name(rest: &str) -> Option<&str> {
+    let rest = rest.trim();
+    let (open, close) = match rest.chars().next()? {
+        '"' => ('"', '"'),
+        '<' => ('<', '>'),
+        _   => return None,
+    };
+    let name = rest.strip_prefix(open)?;
+    let end = name.find(close)?;
+    if name[end + 1..].trim().is_empty() && end > 0 { Some(&name[..end]) } else { None }
+}
+
+// Removes `.` and `..` where possible, so that a file is recognised no matter how it is reached
+fn normalize(path: &Path) -> String {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => { },
+            Component::ParentDir if matches!(normalized.components().next_back(), Some(Component::Normal(_))) => {
+                normalized.pop();
+            },
+            c => normalized.push(c),
+        }
+    }
+    normalized.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::{self, HeadlessContext};
+    use std::collections::HashMap;
+
+    fn process(preprocessor: &Preprocessor, files: &[(&str, &str)]) -> Result<PreprocessedSource, ShaderError> {
+        let files: HashMap<&str, &str> = files.iter().copied().collect();
+        preprocessor.process(files.keys().find(|p| p.ends_with(".vert")).unwrap(), |path| {
+            files.get(path).map(|text| text.to_string())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not found"))
+        })
+    }
+
+    #[test]
+    fn stages_come_from_the_extension() {
+        assert_eq!(ShaderType::from_path("shaders/particles.comp").unwrap(), ShaderType::Compute);
+        assert_eq!(ShaderType::from_path("shaders/simple.vert").unwrap(), ShaderType::Vertex);
+        assert!(matches!(ShaderType::from_path("shaders/frame.glsl"), Err(ShaderError::UnknownStage { .. })));
+    }
+
+    #[test]
+    fn work_groups_cover_every_item() {
+        assert_eq!(work_groups_for([1000, 1, 1], [64, 1, 1]), [16, 1, 1]);
+        assert_eq!(work_groups_for([1024, 17, 1], [64, 8, 1]), [16, 3, 1]);
+        assert_eq!(work_groups_for([0, 1, 1], [64, 1, 1]), [0, 1, 1]);
+    }
+
+    #[test]
+    fn only_compute_programs_are_dispatched() {
+        let _context = match headless::unless_skipped("only_compute_programs_are_dispatched", unsafe { HeadlessContext::new() }) {
+            Some(context) => context,
+            None => return,
+        };
+        unsafe {
+            let compute = ShaderBuilder::new()
+                .compile_shader("#version 430 core\nlayout(local_size_x = 64) in;\nvoid main() {}\n", ShaderType::Compute).unwrap()
+                .link().unwrap();
+            assert_eq!(compute.work_group_size, Some([64, 1, 1]));
+            compute.dispatch_items([1000, 1, 1]).unwrap();
+
+            let vertex = ShaderBuilder::new()
+                .compile_shader("#version 430 core\nvoid main() { gl_Position = vec4(0.0); }\n", ShaderType::Vertex).unwrap()
+                .compile_shader("#version 430 core\nout vec4 color;\nvoid main() { color = vec4(1.0); }\n", ShaderType::Fragment).unwrap()
+                .link().unwrap();
+            assert!(matches!(vertex.dispatch([1, 1, 1]), Err(ShaderError::NotCompute { .. })));
+            assert!(matches!(vertex.dispatch_items([1, 1, 1]), Err(ShaderError::NotCompute { .. })));
+        }
+    }
+
+    #[test]
+    fn uniforms_are_set_by_name_and_type() {
+        let _context = match headless::unless_skipped("uniforms_are_set_by_name_and_type", unsafe { HeadlessContext::new() }) {
+            Some(context) => context,
+            None => return,
+        };
+        unsafe {
+            let shader = ShaderBuilder::new()
+                .compile_shader("#version 430 core
+                    in vec3 position;
+                    uniform vec4 v4; uniform vec3 v3; uniform vec2 v2; uniform float f;
+                    uniform int i; uniform uint u; uniform bool b;
+                    out vec4 value;
+                    void main() {
+                        value = v4 + vec4(v3, f) + vec4(v2, float(i) + float(u), b ? 1.0 : 0.0);
+                        gl_Position = vec4(position, 1.0);
+                    }", ShaderType::Vertex).unwrap()
+                .compile_shader("#version 430 core\nin vec4 value;\nout vec4 color;\nvoid main() { color = value; }\n", ShaderType::Fragment).unwrap()
+                .link().unwrap();
+            assert_eq!(shader.uniforms().len(), 7);
+            assert_eq!(shader.attributes()["position"].kind, GlslType::Vec3);
+
+            shader.set_vec4("v4", &glm::vec4(1.0, 2.0, 3.0, 4.0)).unwrap();
+            shader.set_vec3("v3", &glm::vec3(5.0, 6.0, 7.0)).unwrap();
+            shader.set_vec2("v2", &glm::vec2(8.0, 9.0)).unwrap();
+            shader.set_f32("f", 10.0).unwrap();
+            shader.set_i32("i", -11).unwrap();
+            shader.set_u32("u", 12).unwrap();
+            shader.set_bool("b", true).unwrap();
+
+            let floats = |name: &str, n: usize| {
+                let mut value = [0.0f32; 4];
+                gl::GetUniformfv(shader.program_id, shader.get_uniform_location(name), value.as_mut_ptr());
+                value[..n].to_vec()
+            };
+            assert_eq!(floats("v4", 4), [1.0, 2.0, 3.0, 4.0]);
+            assert_eq!(floats("v3", 3), [5.0, 6.0, 7.0]);
+            assert_eq!(floats("v2", 2), [8.0, 9.0]);
+            assert_eq!(floats("f", 1), [10.0]);
+            let (mut i, mut u, mut b) = (0, 0, 0);
+            gl::GetUniformiv(shader.program_id, shader.get_uniform_location("i"), &mut i);
+            gl::GetUniformuiv(shader.program_id, shader.get_uniform_location("u"), &mut u);
+            gl::GetUniformiv(shader.program_id, shader.get_uniform_location("b"), &mut b);
+            assert_eq!((i, u, b), (-11, 12, 1));
+
+            assert_eq!(shader.get_uniform_location("missing"), -1);
+            assert!(matches!(shader.set_f32("v3", 1.0), Err(UniformError::WrongType { found: GlslType::Vec3, .. })));
+        }
+    }
+
+    #[test]
+    fn defines_reach_the_attached_files() {
+        let _context = match headless::unless_skipped("defines_reach_the_attached_files", unsafe { HeadlessContext::new() }) {
+            Some(context) => context,
+            None => return,
+        };
+        let path = std::env::temp_dir().join(format!("gloom-rs-define-{}.vert", std::process::id()));
+        std::fs::write(&path, "#version 430 core\n#ifndef SCALE\n#error SCALE is not defined\n#endif\nvoid main() { gl_Position = vec4(SCALE); }\n").unwrap();
+        let path = path.to_str().unwrap();
+        unsafe {
+            let without = ShaderBuilder::new().attach_file(path).unwrap().link();
+            assert!(matches!(without, Err(ShaderError::Compile { .. })));
+            ShaderBuilder::new().define("SCALE", "2.0").attach_file(path).unwrap().link().unwrap();
+        }
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn includes_are_pasted_in_with_line_directives() {
+        let processed = process(&Preprocessor::new(), &[
+            ("shaders/main.vert", "#version 430 core\n#include \"lib/light.glsl\"\nvoid main() {}\n"),
+            ("shaders/lib/light.glsl", "float light() { return 1.0; }\n"),
+        ]).unwrap();
+        assert_eq!(processed.source, "#version 430 core\n#line 1 1\nfloat light() { return 1.0; }\n#line 3 0\nvoid main() {}\n");
+        assert_eq!(processed.files[1].path, "shaders/lib/light.glsl");
+    }
+
+    #[test]
+    fn pragma_once_files_are_only_included_once() {
+        let processed = process(&Preprocessor::new(), &[
+            ("a.vert", "#include \"common.glsl\"\n#include \"./common.glsl\"\n"),
+            ("common.glsl", "#pragma once\nconst float PI = 3.14159;\n"),
+        ]).unwrap();
+        assert_eq!(processed.source.matches("PI").count(), 1);
+        assert_eq!(processed.files.len(), 2);
+    }
 
-        Shader {
-            program_id: self.program_id
+    #[test]
+    fn include_cycles_are_reported() {
+        let error = process(&Preprocessor::new(), &[
+            ("a.vert", "#include \"b.glsl\"\n"),
+            ("b.glsl", "#include \"c.glsl\"\n"),
+            ("c.glsl", "#include \"b.glsl\"\n"),
+        ]).unwrap_err();
+        match error {
+            ShaderError::IncludeCycle { chain } => assert_eq!(chain, vec!["b.glsl", "c.glsl", "b.glsl"]),
+            e => panic!("Expected a cycle, got {}", e),
         }
     }
+
+    #[test]
+    fn missing_includes_point_at_the_include() {
+        let error = process(&Preprocessor::new(), &[
+            ("a.vert", "#version 430 core\n\n#include \"missing.glsl\"\n"),
+        ]).unwrap_err();
+        assert!(matches!(error, ShaderError::Include { line: 3, ref included, .. } if included == "missing.glsl"));
+        let error = process(&Preprocessor::new(), &[("a.vert", "#include missing.glsl\n")]).unwrap_err();
+        assert!(matches!(error, ShaderError::Preprocess { line: 1, .. }));
+    }
+
+    #[test]
+    fn defines_go_after_the_version() {
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.define("NORMAL_MAPPING", "1");
+        preprocessor.define("LIGHTS", "2");
+        preprocessor.define("LIGHTS", "4");
+        preprocessor.define("EMPTY", "");
+        let processed = process(&preprocessor, &[("a.vert", "// comment\n#version 430 core\nvoid main() {}\n")]).unwrap();
+        assert_eq!(processed.source, "\
+// comment
+#version 430 core
+#line 1 1
+#define NORMAL_MAPPING 1
+#define LIGHTS 4
+#define EMPTY
+#line 3 0
+void main() {}
+");
+        assert_eq!(processed.files[1].path, GENERATED);
+    }
+
+    #[test]
+    fn defines_go_first_without_a_version() {
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.prelude("in vec3 generated;");
+        let processed = process(&preprocessor, &[("a.vert", "void main() {}\n")]).unwrap();
+        assert_eq!(processed.source, "#line 1 1\nin vec3 generated;\n#line 1 0\nvoid main() {}\n");
+    }
 }
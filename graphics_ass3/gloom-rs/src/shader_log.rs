@@ -0,0 +1,248 @@
+// Makes sense of the info logs shader compilers give back. Every driver has its own format, but
+// most of them are one of these:
+//
+//     0(12) : error C0000: syntax error, unexpected '}'          (NVIDIA)
+//     0:12(5): error: `foo' undeclared                          (Mesa)
+//     ERROR: 0:12: 'foo' : undeclared identifier                 (AMD, Intel on Windows, Apple)
+//
+// where 0 is the index of the source string and 12 is the line. Lines of the log which are in
+// none of these formats are kept as they are.
+
+// A file that source string numbers refer to, as it was before preprocessing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceFile {
+    pub path : String,
+    pub text : String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    pub source   : usize,            // The source string number, set with #line
+    pub line     : Option<usize>,    // 1-based, None for messages which are not about a line
+    pub column   : Option<usize>,    // 1-based, only Mesa gives these
+    pub severity : Severity,
+    pub message  : String,
+}
+
+fn severity_of(word: &str) -> Severity {
+    match word.trim().to_ascii_lowercase().as_str() {
+        w if w.starts_with("error") || w.starts_with("fatal") => Severity::Error,
+        w if w.starts_with("warning")                         => Severity::Warning,
+        _                                                     => Severity::Other,
+    }
+}
+
+// Splits off the leading digits of a string
+fn number(s: &str) -> Option<(usize, &str)> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    if end == 0 {
+        return None;
+    }
+    Some((s[..end].parse().ok()?, &s[end..]))
+}
+
+// ERROR: 0:12: message
+fn parse_prefixed(line: &str) -> Option<LogEntry> {
+    let (word, rest) = line.split_once(':')?;
+    let severity = severity_of(word);
+    if severity == Severity::Other {
+        return None;
+    }
+    let (source, rest) = number(rest.trim_start())?;
+    let (line, rest) = number(rest.strip_prefix(':')?)?;
+    let message = rest.strip_prefix(':')?.trim().to_string();
+    Some(LogEntry { source, line: Some(line), column: None, severity, message })
+}
+
+// 0(12) : error C0000: message
+fn parse_nvidia(line: &str) -> Option<LogEntry> {
+    let (source, rest) = number(line)?;
+    let (line, rest) = number(rest.strip_prefix('(')?)?;
+    let rest = rest.strip_prefix(')')?.trim_start().strip_prefix(':')?.trim_start();
+    // The error code after the severity is kept as part of the message
+    let (word, message) = rest.split_once(' ').unwrap_or((rest, ""));
+    let severity = severity_of(word);
+    Some(LogEntry { source, line: Some(line), column: None, severity, message: message.trim().to_string() })
+}
+
+// 0:12(5): error: message
+fn parse_mesa(line: &str) -> Option<LogEntry> {
+    let (source, rest) = number(line)?;
+    let (line, rest) = number(rest.strip_prefix(':')?)?;
+    let (column, rest) = number(rest.strip_prefix('(')?)?;
+    let rest = rest.strip_prefix("):")?;
+    let (word, message) = rest.split_once(':')?;
+    let severity = severity_of(word);
+    Some(LogEntry { source, line: Some(line), column: Some(column), severity, message: message.trim().to_string() })
+}
+
+pub fn parse(log: &str) -> Vec<LogEntry> {
+    log.lines()
+        .map(str::trim_end)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let trimmed = line.trim_start();
+            parse_prefixed(trimmed)
+                .or_else(|| parse_nvidia(trimmed))
+                .or_else(|| parse_mesa(trimmed))
+                .unwrap_or_else(|| LogEntry { source: 0, line: None, column: None, severity: severity_of(trimmed), message: line.to_string() })
+        })
+        .collect()
+}
+
+// The first identifier quoted in a message, like 'foo' or `foo', which drivers use to say which
+// part of the line they are complaining about
+fn quoted_token(message: &str) -> Option<&str> {
+    let start = message.find(['\'', '`', '"'])? + 1;
+    let length = message[start..].find(['\'', '"'])?;
+    let token = &message[start..start + length];
+    if token.trim().is_empty() { None } else { Some(token) }
+}
+
+// Where to put the caret and how wide to make it, as 0-based character columns
+fn marker(entry: &LogEntry, source_line: &str) -> (usize, usize) {
+    if let Some(token) = quoted_token(&entry.message) {
+        if let Some(at) = source_line.find(token) {
+            return (source_line[..at].chars().count(), token.chars().count());
+        }
+    }
+    match entry.column {
+        Some(column) => (column.saturating_sub(1), 1),
+        None => {
+            let indent = source_line.chars().take_while(|c| c.is_whitespace()).count();
+            (indent, 1)
+        },
+    }
+}
+
+// The log, with every message that refers to a line followed by that line of the source and a
+// caret under the problem. `files` are indexed by source string number.
+pub fn annotate(files: &[SourceFile], log: &str) -> String {
+    let mut out = String::new();
+    for entry in parse(log) {
+        let unknown = format!("<source {}>", entry.source);
+        let (path, source_lines): (&str, Vec<&str>) = match files.get(entry.source) {
+            Some(file) => (file.path.as_str(), file.text.lines().collect()),
+            None       => (unknown.as_str(), vec![]),
+        };
+        let severity = match entry.severity {
+            Severity::Error   => "error: ",
+            Severity::Warning => "warning: ",
+            Severity::Other   => "",
+        };
+        let source_line = entry.line
+            .filter(|&line| line >= 1)
+            .and_then(|line| Some((line, *source_lines.get(line - 1)?)));
+        match source_line {
+            Some((line, text)) => {
+                let text = text.replace('\t', "    ");
+                let gutter = line.to_string().len();
+                let (start, width) = marker(&entry, &text);
+                out += &format!("{}:{}: {}{}\n", path, line, severity, entry.message);
+                out += &format!("{} | {}\n", line, text);
+                out += &format!("{} | {}{}\n", " ".repeat(gutter), " ".repeat(start), "^".repeat(width));
+            },
+            None => match entry.line {
+                Some(line) => out += &format!("{}:{}: {}{}\n", path, line, severity, entry.message),
+                None       => out += &format!("{}\n", entry.message),
+            },
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(path: &str, text: &str) -> Vec<SourceFile> {
+        vec![SourceFile { path: path.to_string(), text: text.to_string() }]
+    }
+
+    const SOURCE: &str = "#version 430 core\n\nin layout(location=0) vec3 position;\n\nvoid main()\n{\n    gl_Position = vec4(positon, 1.0)\n}\n";
+
+    const NVIDIA_LOG: &str = "\
+0(7) : error C1008: undefined variable \"positon\"
+0(8) : error C0000: syntax error, unexpected '}', expecting ',' or ';' at token \"}\"
+";
+    const MESA_LOG: &str = "\
+0:7(24): error: `positon' undeclared
+0:7(21): error: no matching function for call to `vec4(error, float)'
+";
+    const AMD_LOG: &str = "\
+ERROR: 0:7: 'positon' : undeclared identifier
+WARNING: 0:3: 'position' : unused input
+ERROR: 2 compilation errors.  No code generated.
+";
+
+    #[test]
+    fn nvidia_logs_are_parsed() {
+        let entries = parse(NVIDIA_LOG);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], LogEntry {
+            source: 0, line: Some(7), column: None, severity: Severity::Error,
+            message: "C1008: undefined variable \"positon\"".to_string(),
+        });
+        assert_eq!(entries[1].line, Some(8));
+    }
+
+    #[test]
+    fn mesa_logs_are_parsed_with_columns() {
+        let entries = parse(MESA_LOG);
+        assert_eq!(entries[0], LogEntry {
+            source: 0, line: Some(7), column: Some(24), severity: Severity::Error,
+            message: "`positon' undeclared".to_string(),
+        });
+        assert_eq!(entries[1].column, Some(21));
+    }
+
+    #[test]
+    fn prefixed_logs_are_parsed_and_summaries_kept() {
+        let entries = parse(AMD_LOG);
+        assert_eq!(entries.len(), 3);
+        assert_eq!((entries[0].line, entries[0].severity), (Some(7), Severity::Error));
+        assert_eq!(entries[0].message, "'positon' : undeclared identifier");
+        assert_eq!((entries[1].line, entries[1].severity), (Some(3), Severity::Warning));
+        assert_eq!(entries[2].line, None);
+        assert_eq!(entries[2].message, "ERROR: 2 compilation errors.  No code generated.");
+    }
+
+    #[test]
+    fn the_offending_token_is_underlined() {
+        let expected = "\
+simple.vert:7: error: 'positon' : undeclared identifier
+7 |     gl_Position = vec4(positon, 1.0)
+  |                        ^^^^^^^
+";
+        let annotated = annotate(&files("simple.vert", SOURCE), AMD_LOG);
+        assert!(annotated.starts_with(expected), "{}", annotated);
+    }
+
+    #[test]
+    fn mesa_columns_are_used_without_a_token() {
+        let annotated = annotate(&files("simple.vert", SOURCE), "0:6(1): error: syntax error\n");
+        assert_eq!(annotated, "simple.vert:6: error: syntax error\n6 | {\n  | ^\n");
+    }
+
+    #[test]
+    fn lines_outside_the_source_are_not_annotated() {
+        let annotated = annotate(&files("simple.vert", SOURCE), "0(99) : warning C7050: something\n");
+        assert_eq!(annotated, "simple.vert:99: warning: C7050: something\n");
+    }
+
+    #[test]
+    fn source_string_numbers_pick_the_file() {
+        let mut sources = files("simple.frag", "#version 430 core\n#include \"light.glsl\"\n");
+        sources.push(SourceFile { path: "light.glsl".to_string(), text: "float light()\n{\n    return 1\n}\n".to_string() });
+        let annotated = annotate(&sources, "1(4) : error C0000: syntax error, unexpected '}'\n");
+        assert_eq!(annotated, "light.glsl:4: error: C0000: syntax error, unexpected '}'\n4 | }\n  | ^\n");
+        assert_eq!(annotate(&sources, "ERROR: 5:1: 'x' : bad\n"), "<source 5>:1: error: 'x' : bad\n");
+    }
+}
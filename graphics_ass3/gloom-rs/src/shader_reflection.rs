@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ptr;
+
+// Asks a linked program which uniforms and attributes it actually uses, so they can be set by
+// name instead of by a location which has to be kept in sync with the shader by hand. Anything
+// the compiler optimized away because it is never used is not active, and does not show up.
+
+// The GLSL types of uniforms and attributes, as far as the setters on Shader care about them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GlslType {
+    Float,
+    Vec2,
+    Vec3,
+    Vec4,
+    Int,
+    IVec2,
+    IVec3,
+    IVec4,
+    UInt,
+    Bool,
+    Mat3,
+    Mat4,
+    Sampler2D,
+    Other(gl::types::GLenum),
+}
+
+impl From<gl::types::GLenum> for GlslType {
+    fn from(kind: gl::types::GLenum) -> Self {
+        match kind {
+            gl::FLOAT        => GlslType::Float,
+            gl::FLOAT_VEC2   => GlslType::Vec2,
+            gl::FLOAT_VEC3   => GlslType::Vec3,
+            gl::FLOAT_VEC4   => GlslType::Vec4,
+            gl::INT          => GlslType::Int,
+            gl::INT_VEC2     => GlslType::IVec2,
+            gl::INT_VEC3     => GlslType::IVec3,
+            gl::INT_VEC4     => GlslType::IVec4,
+            gl::UNSIGNED_INT => GlslType::UInt,
+            gl::BOOL         => GlslType::Bool,
+            gl::FLOAT_MAT3   => GlslType::Mat3,
+            gl::FLOAT_MAT4   => GlslType::Mat4,
+            gl::SAMPLER_2D   => GlslType::Sampler2D,
+            other            => GlslType::Other(other),
+        }
+    }
+}
+
+impl fmt::Display for GlslType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            GlslType::Float     => "float",
+            GlslType::Vec2      => "vec2",
+            GlslType::Vec3      => "vec3",
+            GlslType::Vec4      => "vec4",
+            GlslType::Int       => "int",
+            GlslType::IVec2     => "ivec2",
+            GlslType::IVec3     => "ivec3",
+            GlslType::IVec4     => "ivec4",
+            GlslType::UInt      => "uint",
+            GlslType::Bool      => "bool",
+            GlslType::Mat3      => "mat3",
+            GlslType::Mat4      => "mat4",
+            GlslType::Sampler2D => "sampler2D",
+            GlslType::Other(e)  => return write!(f, "GL type 0x{:04X}", e),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// An active uniform or attribute
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Variable {
+    pub location : i32,              // -1 for uniforms in a uniform block, which have none
+    pub kind     : GlslType,
+    pub size     : i32,              // The number of elements, for arrays
+}
+
+// An active uniform or shader storage block, which reads its members from a buffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub index : u32,
+    pub size  : usize,               // In bytes, as the driver lays it out. Without any runtime
+                                     // sized array at the end, for storage blocks
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UniformError {
+    Unknown { name: String, program: String, suggestion: Option<String> },
+    WrongType { name: String, program: String, expected: GlslType, found: GlslType },
+    InBlock { name: String, program: String },   // Set through a uniform buffer instead
+    UnknownBlock { name: String, program: String, suggestion: Option<String> },
+    BlockSize { name: String, program: String, expected: usize, found: usize },
+}
+
+impl fmt::Display for UniformError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UniformError::Unknown { name, program, suggestion } => {
+                write!(f, "{} has no active uniform named {:?}", program, name)?;
+                match suggestion {
+                    Some(suggestion) => write!(f, ", did you mean {:?}?", suggestion),
+                    None => write!(f, " (it may have been optimized away if the shader does not use it)"),
+                }
+            },
+            UniformError::WrongType { name, program, expected, found } =>
+                write!(f, "Uniform {:?} in {} is a {}, but was set as a {}", name, program, found, expected),
+            UniformError::InBlock { name, program } =>
+                write!(f, "Uniform {:?} in {} is part of a uniform block, and has to be set through its buffer", name, program),
+            UniformError::UnknownBlock { name, program, suggestion } => {
+                write!(f, "{} has no active uniform block named {:?}", program, name)?;
+                match suggestion {
+                    Some(suggestion) => write!(f, ", did you mean {:?}?", suggestion),
+                    None => Ok(()),
+                }
+            },
+            UniformError::BlockSize { name, program, expected, found } =>
+                write!(f, "Uniform block {:?} in {} takes {} bytes, but the buffer bound to it holds {}; do the Rust and GLSL declarations match?", name, program, found, expected),
+        }
+    }
+}
+
+impl std::error::Error for UniformError {}
+
+type GetActive = unsafe fn(u32, u32, i32, *mut i32, *mut i32, *mut gl::types::GLenum, *mut gl::types::GLchar);
+type GetLocation = unsafe fn(u32, *const gl::types::GLchar) -> i32;
+
+unsafe fn reflect(program_id: u32, count: gl::types::GLenum, max_length: gl::types::GLenum, get_active: GetActive, get_location: GetLocation) -> HashMap<String, Variable> {
+    let mut variables = HashMap::new();
+    let (mut n, mut longest) = (0, 0);
+    gl::GetProgramiv(program_id, count, &mut n);
+    gl::GetProgramiv(program_id, max_length, &mut longest);
+    let mut name = vec![0u8; longest.max(1) as usize];
+    for i in 0..n.max(0) as u32 {
+        let (mut length, mut size, mut kind) = (0, 0, 0);
+        get_active(program_id, i, name.len() as i32, &mut length, &mut size, &mut kind, name.as_mut_ptr() as *mut gl::types::GLchar);
+        let full_name = String::from_utf8_lossy(&name[..length.max(0) as usize]).into_owned();
+        let location = get_location(program_id, name.as_ptr() as *const gl::types::GLchar);
+        let variable = Variable { location, kind: kind.into(), size };
+        // Arrays are reported as `lights[0]`, but are easier to look up without the index
+        if let Some(array) = full_name.strip_suffix("[0]") {
+            variables.insert(array.to_string(), variable);
+        }
+        variables.insert(full_name, variable);
+    }
+    variables
+}
+
+pub unsafe fn active_uniforms(program_id: u32) -> HashMap<String, Variable> {
+    reflect(program_id, gl::ACTIVE_UNIFORMS, gl::ACTIVE_UNIFORM_MAX_LENGTH, gl::GetActiveUniform, gl::GetUniformLocation)
+}
+
+pub unsafe fn active_attributes(program_id: u32) -> HashMap<String, Variable> {
+    reflect(program_id, gl::ACTIVE_ATTRIBUTES, gl::ACTIVE_ATTRIBUTE_MAX_LENGTH, gl::GetActiveAttrib, gl::GetAttribLocation)
+}
+
+pub unsafe fn active_uniform_blocks(program_id: u32) -> HashMap<String, Block> {
+    let mut blocks = HashMap::new();
+    let (mut n, mut longest) = (0, 0);
+    gl::GetProgramiv(program_id, gl::ACTIVE_UNIFORM_BLOCKS, &mut n);
+    gl::GetProgramiv(program_id, gl::ACTIVE_UNIFORM_BLOCK_MAX_NAME_LENGTH, &mut longest);
+    let mut name = vec![0u8; longest.max(1) as usize];
+    for index in 0..n.max(0) as u32 {
+        let (mut length, mut size) = (0, 0);
+        gl::GetActiveUniformBlockName(program_id, index, name.len() as i32, &mut length, name.as_mut_ptr() as *mut gl::types::GLchar);
+        gl::GetActiveUniformBlockiv(program_id, index, gl::UNIFORM_BLOCK_DATA_SIZE, &mut size);
+        let block_name = String::from_utf8_lossy(&name[..length.max(0) as usize]).into_owned();
+        blocks.insert(block_name, Block { index, size: size.max(0) as usize });
+    }
+    blocks
+}
+
+pub unsafe fn active_storage_blocks(program_id: u32) -> HashMap<String, Block> {
+    let mut blocks = HashMap::new();
+    let (mut n, mut longest) = (0, 0);
+    gl::GetProgramInterfaceiv(program_id, gl::SHADER_STORAGE_BLOCK, gl::ACTIVE_RESOURCES, &mut n);
+    gl::GetProgramInterfaceiv(program_id, gl::SHADER_STORAGE_BLOCK, gl::MAX_NAME_LENGTH, &mut longest);
+    let mut name = vec![0u8; longest.max(1) as usize];
+    for index in 0..n.max(0) as u32 {
+        let (mut length, mut size) = (0, 0);
+        gl::GetProgramResourceName(program_id, gl::SHADER_STORAGE_BLOCK, index, name.len() as i32, &mut length, name.as_mut_ptr() as *mut gl::types::GLchar);
+        gl::GetProgramResourceiv(program_id, gl::SHADER_STORAGE_BLOCK, index, 1, &gl::BUFFER_DATA_SIZE, 1, ptr::null_mut(), &mut size);
+        let block_name = String::from_utf8_lossy(&name[..length.max(0) as usize]).into_owned();
+        blocks.insert(block_name, Block { index, size: size.max(0) as usize });
+    }
+    blocks
+}
+
+// The number of single character edits to get from one string to the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + (ca != cb) as usize;
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// The known name closest to a misspelled one, if any is close enough to be what was meant
+pub fn suggest<'a>(name: &str, known: impl Iterator<Item = &'a String>) -> Option<String> {
+    known
+        .map(|candidate| {
+            let distance = if candidate.eq_ignore_ascii_case(name) { 0 } else { edit_distance(name, candidate) };
+            (distance, candidate)
+        })
+        .filter(|&(distance, candidate)| distance <= 2.max(candidate.len() / 4))
+        .min()
+        .map(|(_, candidate)| candidate.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("M_WP", "M_WP"), 0);
+        assert_eq!(edit_distance("M_Wp", "M_WP"), 1);
+        assert_eq!(edit_distance("M_Normal", "M_Normals"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn misspelled_names_get_the_closest_suggestion() {
+        let known: Vec<String> = ["M_Mod", "M_WP", "M_Normal"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(suggest("M_Nromal", known.iter()), Some("M_Normal".to_string()));
+        assert_eq!(suggest("m_wp", known.iter()), Some("M_WP".to_string()));
+        assert_eq!(suggest("time", known.iter()), None);
+    }
+}
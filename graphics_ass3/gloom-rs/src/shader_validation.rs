@@ -0,0 +1,286 @@
+// Checks the shaders in shaders/ without a GPU, so that mistakes in them fail `cargo test` instead
+// of panicking at startup. Every file is run through our preprocessor like ShaderBuilder does, and
+// then parsed and validated by naga's GLSL front end.
+//
+// naga reads the Vulkan flavour of GLSL, so the source is adapted a little first, see for_naga.
+// None of the changes affect the parts which are checked.
+
+use std::path::Path;
+
+use naga::front::glsl::{Frontend, Options};
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+use naga::{Binding, Module, ScalarKind, ShaderStage, TypeInner};
+
+use crate::renderer;
+use crate::shader::{Preprocessor, ShaderType};
+
+const SHADER_DIR: &str = "shaders";
+
+// The files in shaders/, sorted so that failures are reported in the same order every time
+fn shader_files() -> Vec<String> {
+    let mut files: Vec<String> = std::fs::read_dir(SHADER_DIR)
+        .expect("Could not list the shader directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_file())
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    files
+}
+
+fn naga_stage(stage: ShaderType) -> Option<ShaderStage> {
+    match stage {
+        ShaderType::Vertex   => Some(ShaderStage::Vertex),
+        ShaderType::Fragment => Some(ShaderStage::Fragment),
+        ShaderType::Compute  => Some(ShaderStage::Compute),
+        _                    => None,   // naga has no tessellation or geometry shaders
+    }
+}
+
+// The ways a file is compiled by the application, as a name and a preprocessor
+fn permutations(stage: ShaderType) -> Vec<(&'static str, Preprocessor)> {
+    let mut permutations = vec![("plain", Preprocessor::new())];
+    if stage == ShaderType::Vertex {
+        let mut instanced = Preprocessor::new();
+        instanced.prelude(&renderer::instancing_prelude());
+        permutations.push(("instanced", instanced));
+    }
+    permutations
+}
+
+// Makes our GLSL acceptable to naga:
+//
+//   * naga only takes #version 440 and up. We do not use anything which changed since 430.
+//   * Vulkan wants a binding for every uniform and buffer, where OpenGL lets us bind them from
+//     Rust. Those without one get made up bindings, which do not matter to the checks.
+//   * naga does not allow matrices as inputs, like the per-instance matrices. They are read as
+//     one vector per column instead, and put back together with a macro.
+//
+// naga's messages quote the adapted source, so it does not matter that the last one adds lines.
+fn for_naga(source: &str) -> String {
+    let mut binding = 100;
+    let mut adapted = String::new();
+    for line in source.lines() {
+        let trimmed = line.trim_start();
+        if let Some(columns) = matrix_input(trimmed) {
+            adapted += &columns;
+            continue;
+        }
+        let is_resource = trimmed.starts_with("uniform ") || trimmed.starts_with("buffer ");
+        let is_resource_with_layout = trimmed.starts_with("layout")
+            && (trimmed.contains(") uniform") || trimmed.contains(") buffer"))
+            && !trimmed.contains("binding");
+        if trimmed.starts_with("#version") {
+            adapted += "#version 450 core";
+        } else if is_resource {
+            adapted += &format!("layout(binding={}) {}", binding, trimmed);
+            binding += 1;
+        } else if is_resource_with_layout {
+            let close = trimmed.find(')').unwrap();
+            adapted += &format!("{}, binding={}{}", &trimmed[..close], binding, &trimmed[close..]);
+            binding += 1;
+        } else {
+            adapted += line;
+        }
+        adapted += "\n";
+    }
+    adapted
+}
+
+// `in layout(location=6) mat4 m;` as four vec4 inputs at 6 to 9 and a `#define m mat4(...)`
+fn matrix_input(line: &str) -> Option<String> {
+    let rest = line.strip_prefix("in layout(location=")?;
+    let (location, rest) = rest.split_once(')')?;
+    let location: u32 = location.trim().parse().ok()?;
+    let (ty, name) = rest.trim().strip_suffix(';')?.split_once(' ')?;
+    let size = ty.strip_prefix("mat")?;
+    let (columns, rows) = match size.split_once('x') {
+        Some((columns, rows)) => (columns.parse::<u32>().ok()?, rows.parse::<u32>().ok()?),
+        None => (size.parse().ok()?, size.parse().ok()?),
+    };
+    let name = name.trim();
+    let mut lines = String::new();
+    let column_names: Vec<String> = (0..columns).map(|i| format!("{}_column{}", name, i)).collect();
+    for (i, column) in column_names.iter().enumerate() {
+        lines += &format!("in layout(location={}) vec{} {};\n", location + i as u32, rows, column);
+    }
+    lines += &format!("#define {} {}({})\n", name, ty, column_names.join(", "));
+    Some(lines)
+}
+
+// Preprocesses, parses and validates one permutation of a file
+fn compile(path: &str, stage: ShaderStage, preprocessor: &Preprocessor) -> Result<Module, String> {
+    let processed = preprocessor.process(path, |p| std::fs::read_to_string(p))
+        .map_err(|e| e.to_string())?;
+    let source = for_naga(&processed.source);
+    let module = Frontend::default()
+        .parse(&Options::from(stage), &source)
+        .map_err(|e| e.emit_to_string(&source))?;
+    Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(&module)
+        .map_err(|e| e.emit_to_string(&source))?;
+    Ok(module)
+}
+
+// The user defined inputs or outputs of the entry point, as (location, name, type)
+fn interface(module: &Module, outputs: bool) -> Vec<(u32, String, TypeInner)> {
+    let mut variables = vec![];
+    let mut add = |binding: &Option<Binding>, name: &Option<String>, ty: naga::Handle<naga::Type>| {
+        if let Some(Binding::Location { location, .. }) = binding {
+            variables.push((*location, name.clone().unwrap_or_default(), module.types[ty].inner.clone()));
+        }
+    };
+    let function = &module.entry_points[0].function;
+    if outputs {
+        if let Some(result) = &function.result {
+            match &module.types[result.ty].inner {
+                TypeInner::Struct { members, .. } => {
+                    for member in members {
+                        add(&member.binding, &member.name, member.ty);
+                    }
+                },
+                _ => add(&result.binding, &None, result.ty),
+            }
+        }
+    } else {
+        for argument in &function.arguments {
+            add(&argument.binding, &argument.name, argument.ty);
+        }
+    }
+    variables.sort_by_key(|&(location, _, _)| location);
+    variables
+}
+
+// The GLSL name of a type, for the types that can be passed between stages
+fn glsl_type(inner: &TypeInner) -> String {
+    let prefix = |kind| match kind {
+        ScalarKind::Sint => "i",
+        ScalarKind::Uint => "u",
+        ScalarKind::Bool => "b",
+        _                => "",
+    };
+    match inner {
+        TypeInner::Scalar(scalar) => match scalar.kind {
+            ScalarKind::Sint => "int".to_string(),
+            ScalarKind::Uint => "uint".to_string(),
+            ScalarKind::Bool => "bool".to_string(),
+            _                => "float".to_string(),
+        },
+        TypeInner::Vector { size, scalar } => format!("{}vec{}", prefix(scalar.kind), *size as u8),
+        TypeInner::Matrix { columns, rows, .. } => format!("mat{}x{}", *columns as u8, *rows as u8),
+        other => format!("{:?}", other),
+    }
+}
+
+// Every input of the fragment shader has to be written by the vertex shader, at the same location
+// and with the same type. Outputs nobody reads are fine.
+fn check_interface(vertex_path: &str, vertex: &Module, fragment_path: &str, fragment: &Module) -> Vec<String> {
+    let outputs = interface(vertex, true);
+    let mut problems = vec![];
+    for (location, name, ty) in interface(fragment, false) {
+        match outputs.iter().find(|(l, _, _)| *l == location) {
+            None => problems.push(format!(
+                "{} reads {} {} from location {}, which {} does not write",
+                fragment_path, glsl_type(&ty), name, location, vertex_path)),
+            Some((_, out_name, out_ty)) if *out_ty != ty => problems.push(format!(
+                "{} reads {} {} from location {}, but {} writes {} {} there",
+                fragment_path, glsl_type(&ty), name, location, vertex_path, glsl_type(out_ty), out_name)),
+            Some(_) => { },
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader::ShaderError;
+
+    #[test]
+    fn the_shaders_are_stages_and_includes() {
+        let path = |name| format!("{}/{}", SHADER_DIR, name);
+        assert_eq!(ShaderType::from_path(&path("simple.vert")).unwrap(), ShaderType::Vertex);
+        assert_eq!(ShaderType::from_path(&path("simple.frag")).unwrap(), ShaderType::Fragment);
+        // Not a stage of its own, so it is only checked as part of the shaders which include it
+        assert!(matches!(ShaderType::from_path(&path("frame.glsl")), Err(ShaderError::UnknownStage { .. })));
+        for stage in ["simple.vert", "simple.frag"] {
+            let processed = Preprocessor::new().process(&path(stage), |p| std::fs::read_to_string(p)).unwrap();
+            assert!(processed.files.iter().any(|file| file.path == path("frame.glsl")), "{} does not include frame.glsl", stage);
+        }
+    }
+
+    #[test]
+    fn every_shader_compiles() {
+        let mut problems = vec![];
+        for path in shader_files() {
+            let stage = match ShaderType::from_path(&path) {
+                Ok(stage) => stage,
+                Err(_) => continue,      // Includes are checked as part of the files including them
+            };
+            let naga_stage = match naga_stage(stage) {
+                Some(naga_stage) => naga_stage,
+                None => {
+                    eprintln!("Skipping {}, naga can not check {} shaders", path, stage);
+                    continue;
+                },
+            };
+            for (name, preprocessor) in permutations(stage) {
+                if let Err(e) = compile(&path, naga_stage, &preprocessor) {
+                    problems.push(format!("{} ({}):\n{}", path, name, e));
+                }
+            }
+        }
+        assert!(problems.is_empty(), "\n{}", problems.join("\n"));
+    }
+
+    // A .vert and a .frag with the same name are linked together
+    #[test]
+    fn vertex_outputs_match_fragment_inputs() {
+        let mut problems = vec![];
+        for fragment_path in shader_files().into_iter().filter(|path| path.ends_with(".frag")) {
+            let vertex_path = fragment_path.replace(".frag", ".vert");
+            if !Path::new(&vertex_path).exists() {
+                continue;
+            }
+            let fragment = match compile(&fragment_path, ShaderStage::Fragment, &Preprocessor::new()) {
+                Ok(module) => module,
+                Err(_) => continue,      // Reported by every_shader_compiles
+            };
+            for (_, preprocessor) in permutations(ShaderType::Vertex) {
+                if let Ok(vertex) = compile(&vertex_path, ShaderStage::Vertex, &preprocessor) {
+                    problems.extend(check_interface(&vertex_path, &vertex, &fragment_path, &fragment));
+                }
+            }
+        }
+        problems.dedup();
+        assert!(problems.is_empty(), "\n{}", problems.join("\n"));
+    }
+
+    #[test]
+    fn mismatched_locations_and_types_are_caught() {
+        let vertex_source = "#version 450 core\nout layout(location=1) vec4 colour_out;\nout layout(location=3) vec3 normal_out;\nvoid main() { colour_out = vec4(1.0); normal_out = vec3(0.0); gl_Position = vec4(0.0); }\n";
+        let fragment_source = "#version 450 core\nin layout(location=1) vec3 colour_out;\nin layout(location=2) vec3 normal_out;\nout vec4 color;\nvoid main() { color = vec4(colour_out + normal_out, 1.0); }\n";
+        let vertex = Frontend::default().parse(&Options::from(ShaderStage::Vertex), vertex_source).unwrap();
+        let fragment = Frontend::default().parse(&Options::from(ShaderStage::Fragment), fragment_source).unwrap();
+        assert_eq!(check_interface("a.vert", &vertex, "a.frag", &fragment), vec![
+            "a.frag reads vec3 colour_out from location 1, but a.vert writes vec4 colour_out there".to_string(),
+            "a.frag reads vec3 normal_out from location 2, which a.vert does not write".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn matrix_inputs_are_split_into_columns() {
+        assert_eq!(for_naga("in layout(location=10) mat3 instance_normal;\n"), concat!(
+            "in layout(location=10) vec3 instance_normal_column0;\n",
+            "in layout(location=11) vec3 instance_normal_column1;\n",
+            "in layout(location=12) vec3 instance_normal_column2;\n",
+            "#define instance_normal mat3(instance_normal_column0, instance_normal_column1, instance_normal_column2)\n",
+        ));
+    }
+
+    #[test]
+    fn adapting_for_naga_keeps_the_lines() {
+        let source = "#version 430 core\nuniform mat4 M_Mod;\nlayout(std140) uniform Frame { mat4 vp; };\nlayout(std140, binding=2) uniform Other { float x; };\n";
+        assert_eq!(for_naga(source), "#version 450 core\nlayout(binding=100) uniform mat4 M_Mod;\nlayout(std140, binding=101) uniform Frame { mat4 vp; };\nlayout(std140, binding=2) uniform Other { float x; };\n");
+    }
+}
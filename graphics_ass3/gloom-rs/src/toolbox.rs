@@ -1,6 +1,11 @@
 extern crate nalgebra_glm as glm;
 use std::f64::consts::PI;
 
+use serde::{Serialize, Deserialize};
+
+use crate::rotation;
+use crate::scene_graph::SceneNode;
+
 pub struct Heading {
     pub x     : f32,
     pub z     : f32,
@@ -34,3 +39,28 @@ pub fn simple_heading_animation(time: f32) -> Heading {
         yaw   : yaw   as f32,
     }
 }
+
+// Animations which can be attached to a SceneNode, and stored along with it in a scene file
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Animation {
+    // Rotate about an axis through the reference point, at a given speed in radians per second
+    Spin { axis: [f32; 3], speed: f32 },
+    // Fly along the path of simple_heading_animation, some seconds ahead of everyone else
+    Heading { time_offset: f32 },
+}
+
+impl Animation {
+    pub fn apply(&self, node: &mut SceneNode, time: f32) {
+        match *self {
+            Animation::Spin { axis, speed } => {
+                node.orientation = rotation::from_axis_angle(&axis.into(), speed * time);
+            },
+            Animation::Heading { time_offset } => {
+                let heading = simple_heading_animation(time + time_offset);
+                node.position.x = heading.x;
+                node.position.z = heading.z;
+                node.set_euler_angles(&glm::vec3(heading.pitch, heading.yaw, heading.roll));
+            },
+        }
+    }
+}
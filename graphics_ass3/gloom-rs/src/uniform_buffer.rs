@@ -0,0 +1,338 @@
+extern crate nalgebra_glm as glm;
+
+use std::marker::PhantomData;
+use std::ptr;
+
+// Uniform buffer objects, for data which is the same for every draw call in a frame, such as the
+// camera. The buffer is filled once per frame, and every program with a matching uniform block
+// reads from it through a binding point, instead of each program getting its own copy.
+//
+// The data is packed by the std140 rules, which are what `layout(std140) uniform Block` uses:
+//
+//   * float, int, uint and bool take 4 bytes and are aligned to 4
+//   * vec2 takes 8 bytes, aligned to 8
+//   * vec3 takes 12 bytes and vec4 16 bytes, both aligned to 16
+//   * Array elements are aligned to 16, and each takes a multiple of 16 bytes
+//   * A matrix is an array of its columns, so a mat3 takes 48 bytes and a mat4 64 bytes
+//   * A struct is aligned to 16, and its size is rounded up to a multiple of 16
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Std140Type {
+    Float,
+    Int,
+    UInt,
+    Bool,
+    Vec2,
+    Vec3,
+    Vec4,
+    Mat3,
+    Mat4,
+    Array(Box<Std140Type>, usize),
+    Struct(Vec<Std140Type>),
+}
+
+fn align_up(offset: usize, alignment: usize) -> usize {
+    offset.div_ceil(alignment) * alignment
+}
+
+impl Std140Type {
+    pub fn alignment(&self) -> usize {
+        match self {
+            Std140Type::Float | Std140Type::Int | Std140Type::UInt | Std140Type::Bool => 4,
+            Std140Type::Vec2                                   => 8,
+            Std140Type::Vec3 | Std140Type::Vec4                => 16,
+            Std140Type::Mat3 | Std140Type::Mat4                => 16,
+            Std140Type::Array(element, _)                      => align_up(element.alignment(), 16),
+            Std140Type::Struct(members) => {
+                align_up(members.iter().map(Std140Type::alignment).max().unwrap_or(1), 16)
+            },
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            Std140Type::Float | Std140Type::Int | Std140Type::UInt | Std140Type::Bool => 4,
+            Std140Type::Vec2                   => 8,
+            Std140Type::Vec3                   => 12,
+            Std140Type::Vec4                   => 16,
+            Std140Type::Mat3                   => 3 * 16,
+            Std140Type::Mat4                   => 4 * 16,
+            Std140Type::Array(element, length) => Std140Type::array_stride(element) * length,
+            Std140Type::Struct(members) => {
+                let end = match (Std140Type::offsets(members).last(), members.last()) {
+                    (Some(offset), Some(last)) => offset + last.size(),
+                    _                          => 0,
+                };
+                align_up(end, self.alignment())
+            },
+        }
+    }
+
+    // The distance between two elements of an array of `element`
+    pub fn array_stride(element: &Std140Type) -> usize {
+        align_up(element.size(), align_up(element.alignment(), 16))
+    }
+
+    // Where each member of a struct or uniform block starts
+    pub fn offsets(members: &[Std140Type]) -> Vec<usize> {
+        let mut end = 0;
+        members.iter()
+            .map(|member| {
+                let offset = align_up(end, member.alignment());
+                end = offset + member.size();
+                offset
+            })
+            .collect()
+    }
+}
+
+// A Rust type which can be written to a uniform buffer. Structs implement write() by pushing
+// each of their fields in the order they are declared in GLSL, and std140_type() with the types
+// of those fields, in the same order.
+pub trait Std140 {
+    fn std140_type() -> Std140Type;
+    fn write(&self, writer: &mut Std140Writer);
+}
+
+#[derive(Default)]
+pub struct Std140Writer {
+    bytes: Vec<u8>,
+}
+
+impl Std140Writer {
+    pub fn new() -> Std140Writer {
+        Std140Writer::default()
+    }
+
+    fn pad_to(&mut self, length: usize) {
+        if self.bytes.len() < length {
+            self.bytes.resize(length, 0);
+        }
+    }
+
+    // Aligns the value, writes it, and pads it out to its full size
+    pub fn push<T: Std140>(&mut self, value: &T) {
+        let kind = T::std140_type();
+        let start = align_up(self.bytes.len(), kind.alignment());
+        self.pad_to(start);
+        value.write(self);
+        self.pad_to(start + kind.size());
+    }
+
+    fn write_bytes(&mut self, bytes: [u8; 4]) {
+        self.bytes.extend_from_slice(&bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl Std140 for f32 {
+    fn std140_type() -> Std140Type { Std140Type::Float }
+    fn write(&self, writer: &mut Std140Writer) { writer.write_bytes(self.to_ne_bytes()) }
+}
+
+impl Std140 for i32 {
+    fn std140_type() -> Std140Type { Std140Type::Int }
+    fn write(&self, writer: &mut Std140Writer) { writer.write_bytes(self.to_ne_bytes()) }
+}
+
+impl Std140 for u32 {
+    fn std140_type() -> Std140Type { Std140Type::UInt }
+    fn write(&self, writer: &mut Std140Writer) { writer.write_bytes(self.to_ne_bytes()) }
+}
+
+impl Std140 for bool {
+    fn std140_type() -> Std140Type { Std140Type::Bool }
+    fn write(&self, writer: &mut Std140Writer) { writer.write_bytes((*self as u32).to_ne_bytes()) }
+}
+
+impl Std140 for glm::Vec2 {
+    fn std140_type() -> Std140Type { Std140Type::Vec2 }
+    fn write(&self, writer: &mut Std140Writer) { self.iter().for_each(|x| x.write(writer)) }
+}
+
+impl Std140 for glm::Vec3 {
+    fn std140_type() -> Std140Type { Std140Type::Vec3 }
+    fn write(&self, writer: &mut Std140Writer) { self.iter().for_each(|x| x.write(writer)) }
+}
+
+impl Std140 for glm::Vec4 {
+    fn std140_type() -> Std140Type { Std140Type::Vec4 }
+    fn write(&self, writer: &mut Std140Writer) { self.iter().for_each(|x| x.write(writer)) }
+}
+
+impl Std140 for glm::Mat3 {
+    fn std140_type() -> Std140Type { Std140Type::Mat3 }
+    fn write(&self, writer: &mut Std140Writer) {
+        for column in self.column_iter() {
+            writer.push(&glm::Vec3::from(column));
+        }
+    }
+}
+
+impl Std140 for glm::Mat4 {
+    fn std140_type() -> Std140Type { Std140Type::Mat4 }
+    fn write(&self, writer: &mut Std140Writer) { self.iter().for_each(|x| x.write(writer)) }
+}
+
+impl<T: Std140, const N: usize> Std140 for [T; N] {
+    fn std140_type() -> Std140Type { Std140Type::Array(Box::new(T::std140_type()), N) }
+    fn write(&self, writer: &mut Std140Writer) {
+        let stride = Std140Type::array_stride(&T::std140_type());
+        let start = writer.bytes.len();
+        for (i, element) in self.iter().enumerate() {
+            writer.pad_to(start + i * stride);
+            writer.push(element);
+        }
+    }
+}
+
+// Packs a value into the bytes a uniform block expects
+pub fn to_std140<T: Std140>(value: &T) -> Vec<u8> {
+    let mut writer = Std140Writer::new();
+    writer.push(value);
+    writer.into_bytes()
+}
+
+// A buffer holding one T, attached to a binding point. Programs read it through a uniform block
+// bound to the same point, see Shader::bind_uniform_buffer.
+pub struct UniformBuffer<T: Std140> {
+    id      : u32,
+    binding : u32,
+    size    : usize,
+    _data   : PhantomData<T>,
+}
+
+impl<T: Std140> UniformBuffer<T> {
+    pub unsafe fn new(binding: u32) -> UniformBuffer<T> {
+        let size = T::std140_type().size();
+        let mut id = 0;
+        gl::GenBuffers(1, &mut id);
+        gl::BindBuffer(gl::UNIFORM_BUFFER, id);
+        gl::BufferData(gl::UNIFORM_BUFFER, size as isize, ptr::null(), gl::DYNAMIC_DRAW);
+        gl::BindBufferBase(gl::UNIFORM_BUFFER, binding, id);
+        UniformBuffer { id, binding, size, _data: PhantomData }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn binding(&self) -> u32 {
+        self.binding
+    }
+
+    // The size of T in bytes, when packed
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Replaces the whole contents of the buffer. Call it once per frame, before drawing
+    pub unsafe fn update(&mut self, value: &T) {
+        let bytes = to_std140(value);
+        debug_assert_eq!(bytes.len(), self.size, "Std140::write does not match Std140::std140_type");
+        gl::BindBuffer(gl::UNIFORM_BUFFER, self.id);
+        gl::BufferSubData(gl::UNIFORM_BUFFER, 0, bytes.len() as isize, bytes.as_ptr() as *const _);
+    }
+}
+
+impl<T: Std140> Drop for UniformBuffer<T> {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteBuffers(1, &self.id) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn std140_offsets_of_every_kind_of_member() {
+        // Offsets as a driver reports them with glGetActiveUniformsiv(GL_UNIFORM_OFFSET):
+        //
+        //     layout(std140) uniform Example {
+        //         float a;       // 0
+        //         vec2 b;        // 8
+        //         vec3 c;        // 16
+        //         struct {
+        //             int d;     // 32
+        //             bool e;    // 36
+        //         } f;           // 32
+        //         float g;       // 48
+        //         float h[2];    // 64
+        //         mat3 i;        // 96
+        //         struct {
+        //             uint j;    // 144
+        //             vec2 k;    // 152
+        //             float l[2];// 160
+        //         } o[2];        // 144
+        //     };                 // 240 bytes in total
+        let inner = Std140Type::Struct(vec![Std140Type::UInt, Std140Type::Vec2, Std140Type::Array(Box::new(Std140Type::Float), 2)]);
+        let members = vec![
+            Std140Type::Float,
+            Std140Type::Vec2,
+            Std140Type::Vec3,
+            Std140Type::Struct(vec![Std140Type::Int, Std140Type::Bool]),
+            Std140Type::Float,
+            Std140Type::Array(Box::new(Std140Type::Float), 2),
+            Std140Type::Mat3,
+            Std140Type::Array(Box::new(inner.clone()), 2),
+        ];
+        assert_eq!(Std140Type::offsets(&members), vec![0, 8, 16, 32, 48, 64, 96, 144]);
+        assert_eq!(Std140Type::offsets(&[Std140Type::UInt, Std140Type::Vec2, Std140Type::Array(Box::new(Std140Type::Float), 2)]), vec![0, 8, 16]);
+        assert_eq!(inner.size(), 48);
+        assert_eq!(Std140Type::Struct(members).size(), 144 + 2 * 48);
+    }
+
+    #[test]
+    fn a_float_after_a_vec3_fills_the_gap() {
+        let members = [Std140Type::Vec3, Std140Type::Float, Std140Type::Vec3, Std140Type::Vec2];
+        assert_eq!(Std140Type::offsets(&members), vec![0, 12, 16, 32]);
+    }
+
+    #[test]
+    fn written_bytes_match_the_layout() {
+        struct Light {
+            direction : glm::Vec3,
+            intensity : f32,
+            colours   : [glm::Vec3; 2],
+            basis     : glm::Mat3,
+        }
+        impl Std140 for Light {
+            fn std140_type() -> Std140Type {
+                Std140Type::Struct(vec![
+                    Std140Type::Vec3,
+                    Std140Type::Float,
+                    Std140Type::Array(Box::new(Std140Type::Vec3), 2),
+                    Std140Type::Mat3,
+                ])
+            }
+            fn write(&self, writer: &mut Std140Writer) {
+                writer.push(&self.direction);
+                writer.push(&self.intensity);
+                writer.push(&self.colours);
+                writer.push(&self.basis);
+            }
+        }
+
+        let light = Light {
+            direction : glm::vec3(1.0, 2.0, 3.0),
+            intensity : 4.0,
+            colours   : [glm::vec3(5.0, 6.0, 7.0), glm::vec3(8.0, 9.0, 10.0)],
+            basis     : glm::mat3(11.0, 14.0, 17.0, 12.0, 15.0, 18.0, 13.0, 16.0, 19.0),
+        };
+        let bytes = to_std140(&light);
+        let floats: Vec<f32> = bytes.chunks(4).map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]])).collect();
+        assert_eq!(bytes.len(), Light::std140_type().size());
+        assert_eq!(floats, vec![
+            1.0, 2.0, 3.0, 4.0,
+            5.0, 6.0, 7.0, 0.0,
+            8.0, 9.0, 10.0, 0.0,
+            11.0, 12.0, 13.0, 0.0,
+            14.0, 15.0, 16.0, 0.0,
+            17.0, 18.0, 19.0, 0.0,
+        ]);
+    }
+}